@@ -0,0 +1,24 @@
+#![no_main]
+
+use doom_bot_client::net_packet::NetPacket;
+use libfuzzer_sys::fuzz_target;
+
+// First byte selects lowres_turn, the rest is a GAMEDATA body.
+fuzz_target!(|data: &[u8]| {
+    let Some((&lowres_turn, body)) = data.split_first() else {
+        return;
+    };
+
+    let mut packet = NetPacket {
+        data: body.to_vec(),
+        pos: 0,
+    };
+    let (Ok(_seq), Ok(num_tics)) = (packet.read_u8(), packet.read_u8()) else {
+        return;
+    };
+    for _ in 0..num_tics {
+        if packet.read_full_ticcmd(lowres_turn & 1 != 0).is_err() {
+            return;
+        }
+    }
+});
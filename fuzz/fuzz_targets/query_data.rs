@@ -0,0 +1,12 @@
+#![no_main]
+
+use doom_bot_client::net_packet::NetPacket;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let mut packet = NetPacket {
+        data: data.to_vec(),
+        pos: 0,
+    };
+    let _ = packet.read_query_data();
+});
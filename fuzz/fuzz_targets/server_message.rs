@@ -0,0 +1,49 @@
+#![no_main]
+
+use doom_bot_client::net_packet::NetPacket;
+use doom_bot_client::net_structs::NetPacketType;
+use libfuzzer_sys::fuzz_target;
+
+// Decodes a whole datagram the way the client does: packet type first,
+// then the body for that type.
+fuzz_target!(|data: &[u8]| {
+    let mut packet = NetPacket {
+        data: data.to_vec(),
+        pos: 0,
+    };
+    let Ok(packet_type) = packet.read_u16() else {
+        return;
+    };
+
+    match NetPacketType::try_from(packet_type) {
+        Ok(NetPacketType::Syn) => {
+            let _ = packet.read_string();
+            let _ = packet.read_protocol();
+        }
+        Ok(NetPacketType::Rejected) | Ok(NetPacketType::ConsoleMessage) => {
+            let _ = packet.read_string();
+        }
+        Ok(NetPacketType::WaitingData) => {
+            let _ = packet.read_wait_data();
+        }
+        Ok(NetPacketType::Launch) => {
+            let _ = packet.read_u8();
+        }
+        Ok(NetPacketType::GameStart) => {
+            let _ = packet.read_settings_and_seed();
+        }
+        Ok(NetPacketType::GameData) => {
+            let _ = packet.read_u8();
+            let _ = packet.read_u8();
+            while packet.read_full_ticcmd(false).is_ok() {}
+        }
+        Ok(NetPacketType::GameDataResend) => {
+            let _ = packet.read_i32();
+            let _ = packet.read_u8();
+        }
+        Ok(NetPacketType::QueryResponse) => {
+            let _ = packet.read_query_data();
+        }
+        _ => {}
+    }
+});
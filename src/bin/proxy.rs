@@ -0,0 +1,429 @@
+//! Transparent UDP proxy that sits between Chocolate Doom clients and a
+//! server. Every packet is forwarded unchanged and logged, decoded, with a
+//! timestamp and its direction, so desyncs can be traced back to what was
+//! actually said on the wire.
+//!
+//! Usage: proxy <listen address> <server address>
+
+use std::collections::HashMap;
+use std::fmt::{self, Write};
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::process;
+use std::time::{Duration, Instant};
+
+use doom_bot_client::net_io;
+use doom_bot_client::net_packet::{FieldContext, NetPacket, PacketError};
+use doom_bot_client::net_structs::*;
+
+// Large enough for any packet Chocolate Doom sends
+const MAX_PACKET_SIZE: usize = 1500;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Direction {
+    ToServer,
+    ToClient,
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Direction::ToServer => write!(f, "C->S"),
+            Direction::ToClient => write!(f, "S->C"),
+        }
+    }
+}
+
+/// A client seen by the proxy. Each gets its own upstream socket, so the
+/// server sees one address per client just as it would without us.
+struct Session {
+    upstream: UdpSocket,
+    /// Turn encoding in use, learnt from the game start packet
+    lowres_turn: bool,
+}
+
+impl Session {
+    fn new(server_addr: SocketAddr) -> io::Result<Self> {
+        let upstream = net_io::bind_udp_for(server_addr)?;
+
+        Ok(Session {
+            upstream,
+            lowres_turn: false,
+        })
+    }
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let (Some(listen_addr), Some(server_addr)) = (args.get(1), args.get(2)) else {
+        eprintln!("Usage: proxy <listen address> <server address>");
+        process::exit(1);
+    };
+
+    let listen_addr = net_io::resolve_address(listen_addr).unwrap_or_else(|e| {
+        eprintln!("Invalid listen address: {}", e);
+        process::exit(1);
+    });
+    let server_addr = net_io::resolve_address(server_addr).unwrap_or_else(|e| {
+        eprintln!("Invalid server address: {}", e);
+        process::exit(1);
+    });
+
+    let listen = UdpSocket::bind(listen_addr).expect("Failed to bind listen socket");
+    listen
+        .set_nonblocking(true)
+        .expect("Failed to set non-blocking");
+
+    println!("Proxy: Forwarding {} to {}", listen_addr, server_addr);
+
+    let start = Instant::now();
+    let mut sessions: HashMap<SocketAddr, Session> = HashMap::new();
+
+    loop {
+        let mut idle = true;
+
+        while let Some((data, client_addr)) = receive(&listen) {
+            idle = false;
+
+            let session = match sessions.get_mut(&client_addr) {
+                Some(session) => session,
+                None => {
+                    println!("Proxy: New client {}", client_addr);
+                    let session =
+                        Session::new(server_addr).expect("Failed to bind upstream socket");
+                    sessions.entry(client_addr).or_insert(session)
+                }
+            };
+
+            log(
+                start,
+                Direction::ToServer,
+                client_addr,
+                &data,
+                &mut session.lowres_turn,
+            );
+            if let Err(e) = session.upstream.send_to(&data, server_addr) {
+                eprintln!("Proxy: Failed to forward to {}: {}", server_addr, e);
+            }
+        }
+
+        for (&client_addr, session) in sessions.iter_mut() {
+            while let Some((data, from)) = receive(&session.upstream) {
+                idle = false;
+
+                if from != server_addr {
+                    continue;
+                }
+
+                log(
+                    start,
+                    Direction::ToClient,
+                    client_addr,
+                    &data,
+                    &mut session.lowres_turn,
+                );
+                if let Err(e) = listen.send_to(&data, client_addr) {
+                    eprintln!("Proxy: Failed to forward to {}: {}", client_addr, e);
+                }
+            }
+        }
+
+        if idle {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+}
+
+/// Receives a datagram if one is waiting.
+fn receive(socket: &UdpSocket) -> Option<(Vec<u8>, SocketAddr)> {
+    let mut buf = [0u8; MAX_PACKET_SIZE];
+    match socket.recv_from(&mut buf) {
+        Ok((size, addr)) => Some((buf[..size].to_vec(), addr)),
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock => None,
+        Err(e) => {
+            eprintln!("Proxy: Error receiving packet: {}", e);
+            None
+        }
+    }
+}
+
+fn log(
+    start: Instant,
+    direction: Direction,
+    client_addr: SocketAddr,
+    data: &[u8],
+    lowres_turn: &mut bool,
+) {
+    let mut packet = NetPacket {
+        data: data.to_vec(),
+        pos: 0,
+    };
+
+    println!(
+        "[{:>10.3}] {} {} {}",
+        start.elapsed().as_secs_f64(),
+        direction,
+        client_addr,
+        describe(&mut packet, direction, lowres_turn)
+    );
+}
+
+/// Decodes a packet into a one-line description for the log.
+fn describe(packet: &mut NetPacket, direction: Direction, lowres_turn: &mut bool) -> String {
+    let Ok(mut packet_type) = packet.read_u16() else {
+        return format!("runt packet of {} bytes", packet.data.len());
+    };
+
+    let mut desc = String::new();
+    if packet_type & NET_RELIABLE_PACKET != 0 {
+        packet_type &= !NET_RELIABLE_PACKET;
+        match packet.read_u8() {
+            Ok(seq) => write!(desc, "reliable seq={} ", seq).unwrap(),
+            Err(e) => return format!("malformed reliable packet: {}", e),
+        }
+    }
+
+    let Ok(packet_type) = NetPacketType::try_from(packet_type) else {
+        return format!("{}unknown packet type {}", desc, packet_type);
+    };
+
+    write!(desc, "{:?}", packet_type).unwrap();
+    match describe_body(packet, packet_type, direction, lowres_turn) {
+        Ok(body) => {
+            if !body.is_empty() {
+                write!(desc, " {}", body).unwrap();
+            }
+
+            let trailing = packet.data.len().saturating_sub(packet.pos);
+            if trailing > 0 {
+                write!(desc, " (+{} trailing bytes)", trailing).unwrap();
+            }
+        }
+        Err(e) => write!(desc, " malformed: {}", e).unwrap(),
+    }
+
+    desc
+}
+
+fn describe_body(
+    packet: &mut NetPacket,
+    packet_type: NetPacketType,
+    direction: Direction,
+    lowres_turn: &mut bool,
+) -> Result<String, PacketError> {
+    let mut desc = String::new();
+
+    match (packet_type, direction) {
+        (NetPacketType::Syn, Direction::ToServer) => {
+            let magic = packet.read_u32().field("magic number")?;
+            let version = packet.read_string().field("client version")?;
+            // Clients before v3.0 send no protocol list
+            let protocol = if magic == NET_OLD_MAGIC_NUMBER {
+                NetProtocol::Unknown
+            } else {
+                packet.read_protocol_list()
+            };
+            let data = packet.read_connect_data()?;
+            let name = packet.read_string().field("player name")?;
+            write!(
+                desc,
+                "magic={:#x} version={:?} protocol={:?} name={:?} {:?}",
+                magic, version, protocol, name, data
+            )
+            .unwrap();
+        }
+        (NetPacketType::Syn, Direction::ToClient) => {
+            let version = packet.read_string().field("server version")?;
+            let protocol = packet.read_protocol();
+            write!(desc, "version={:?} protocol={:?}", version, protocol).unwrap();
+        }
+        (NetPacketType::Rejected | NetPacketType::ConsoleMessage, _) => {
+            write!(desc, "{:?}", packet.read_string()?).unwrap();
+        }
+        (NetPacketType::WaitingData, _) => {
+            let data = packet.read_wait_data()?;
+            let names: Vec<String> = data.player_names[..data.num_players as usize]
+                .iter()
+                .map(|name| name.iter().take_while(|&&c| c != '\0').collect())
+                .collect();
+            write!(
+                desc,
+                "players={}/{} drones={} ready={} controller={} consoleplayer={} names={:?}",
+                data.num_players,
+                data.max_players,
+                data.num_drones,
+                data.ready_players,
+                data.is_controller,
+                data.consoleplayer,
+                names
+            )
+            .unwrap();
+        }
+        (NetPacketType::Launch, Direction::ToClient) => {
+            write!(
+                desc,
+                "num_players={}",
+                packet.read_u8().field("num_players")?
+            )
+            .unwrap();
+        }
+        (NetPacketType::GameStart, _) => {
+            let settings = packet.read_settings_and_seed()?;
+            *lowres_turn = settings.lowres_turn != 0;
+            write!(desc, "{:?}", settings).unwrap();
+        }
+        (NetPacketType::GameData, Direction::ToServer) => {
+            let ackseq = packet.read_u8().field("gamedata ackseq")?;
+            let seq = packet.read_u8().field("gamedata seq")?;
+            let num_tics = packet.read_u8().field("gamedata num_tics")?;
+            write!(desc, "ack={} seq={} tics={}", ackseq, seq, num_tics).unwrap();
+
+            for i in 0..num_tics {
+                let latency = packet.read_i16().field("gamedata latency")?;
+                let diff = packet.read_ticcmd_diff(*lowres_turn)?;
+                write!(
+                    desc,
+                    " [{} latency={}{}]",
+                    seq.wrapping_add(i),
+                    latency,
+                    describe_diff(&diff)
+                )
+                .unwrap();
+            }
+        }
+        (NetPacketType::GameData, Direction::ToClient) => {
+            let seq = packet.read_u8().field("gamedata seq")?;
+            let num_tics = packet.read_u8().field("gamedata num_tics")?;
+            write!(desc, "seq={} tics={}", seq, num_tics).unwrap();
+
+            for i in 0..num_tics {
+                let cmd = packet.read_full_ticcmd(*lowres_turn)?;
+                write!(desc, " [{} latency={}", seq.wrapping_add(i), cmd.latency).unwrap();
+                for (player, diff) in cmd.cmds.iter().enumerate() {
+                    if cmd.playeringame[player] {
+                        write!(desc, " p{}:{{{}}}", player, describe_diff(diff).trim()).unwrap();
+                    }
+                }
+                desc.push(']');
+            }
+        }
+        (NetPacketType::GameDataAck | NetPacketType::ReliableAck, _) => {
+            write!(desc, "ack={}", packet.read_u8().field("ack")?).unwrap();
+        }
+        (NetPacketType::GameDataResend, _) => {
+            let start = packet.read_i32().field("resend start")?;
+            let num_tics = packet.read_u8().field("resend num_tics")?;
+            write!(desc, "start={} tics={}", start, num_tics).unwrap();
+        }
+        (NetPacketType::QueryResponse, _) => {
+            write!(desc, "{:?}", packet.read_query_data()?).unwrap();
+        }
+        _ => {}
+    }
+
+    Ok(desc)
+}
+
+/// Lists the ticcmd fields a diff carries.
+fn describe_diff(diff: &NetTicDiff) -> String {
+    let cmd = &diff.cmd;
+    let mut desc = String::new();
+
+    if diff.diff & NET_TICDIFF_FORWARD != 0 {
+        write!(desc, " fwd={}", cmd.forwardmove).unwrap();
+    }
+    if diff.diff & NET_TICDIFF_SIDE != 0 {
+        write!(desc, " side={}", cmd.sidemove).unwrap();
+    }
+    if diff.diff & NET_TICDIFF_TURN != 0 {
+        write!(desc, " turn={}", cmd.angleturn).unwrap();
+    }
+    if diff.diff & NET_TICDIFF_BUTTONS != 0 {
+        write!(desc, " buttons={:#04x}", cmd.buttons).unwrap();
+    }
+    if diff.diff & NET_TICDIFF_CONSISTANCY != 0 {
+        write!(desc, " consistancy={}", cmd.consistancy).unwrap();
+    }
+    if diff.diff & NET_TICDIFF_CHATCHAR != 0 {
+        write!(desc, " chat={}", cmd.chatchar).unwrap();
+    }
+    if diff.diff & NET_TICDIFF_RAVEN != 0 {
+        write!(desc, " lookfly={} arti={}", cmd.lookfly, cmd.arti).unwrap();
+    }
+    if diff.diff & NET_TICDIFF_STRIFE != 0 {
+        write!(
+            desc,
+            " buttons2={} inventory={}",
+            cmd.buttons2, cmd.inventory
+        )
+        .unwrap();
+    }
+
+    desc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reset(mut packet: NetPacket) -> NetPacket {
+        packet.reset();
+        packet
+    }
+
+    #[test]
+    fn test_describe_client_game_data() {
+        let mut packet = NetPacket::new();
+        packet.write_u16(NetPacketType::GameData as u16);
+        packet.write_u8(3);
+        packet.write_u8(7);
+        packet.write_u8(1);
+        packet.write_i16(25);
+        packet.write_ticcmd_diff(
+            &NetTicDiff {
+                diff: NET_TICDIFF_FORWARD | NET_TICDIFF_CONSISTANCY,
+                cmd: TicCmd {
+                    forwardmove: 50,
+                    consistancy: 9,
+                    ..Default::default()
+                },
+            },
+            false,
+        );
+
+        let mut lowres_turn = false;
+        assert_eq!(
+            describe(&mut reset(packet), Direction::ToServer, &mut lowres_turn),
+            "GameData ack=3 seq=7 tics=1 [7 latency=25 fwd=50 consistancy=9]"
+        );
+    }
+
+    #[test]
+    fn test_game_start_sets_turn_resolution() {
+        let mut packet = NetPacket::new();
+        packet.write_u16(NetPacketType::GameStart as u16 | NET_RELIABLE_PACKET);
+        packet.write_u8(4);
+        packet.write_settings(&GameSettings {
+            ticdup: 1,
+            lowres_turn: 1,
+            num_players: 1,
+            ..Default::default()
+        });
+
+        let mut lowres_turn = false;
+        let desc = describe(&mut reset(packet), Direction::ToClient, &mut lowres_turn);
+        assert!(desc.starts_with("reliable seq=4 GameStart GameSettings {"));
+        assert!(lowres_turn);
+    }
+
+    #[test]
+    fn test_describe_malformed_packet() {
+        let mut packet = NetPacket::new();
+        packet.write_u16(NetPacketType::GameDataResend as u16);
+        packet.write_u16(1);
+
+        let mut lowres_turn = false;
+        let desc = describe(&mut reset(packet), Direction::ToClient, &mut lowres_turn);
+        assert!(desc.starts_with("GameDataResend malformed: truncated resend start"));
+    }
+}
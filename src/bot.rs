@@ -1,14 +1,13 @@
 use crate::net_structs::*;
 
+#[derive(Default)]
 pub struct Bot {
     last_ticcmd: TicCmd,
 }
 
 impl Bot {
     pub fn new() -> Self {
-        Bot {
-            last_ticcmd: TicCmd::default(),
-        }
+        Self::default()
     }
 
     pub fn init(&mut self) {
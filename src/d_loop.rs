@@ -2,6 +2,7 @@ use std::sync::atomic::{AtomicU32, Ordering};
 use std::time::{Instant, SystemTime};
 
 use crate::net_client::NetClient;
+use crate::net_server;
 use crate::net_structs::{GameSettings, TicCmd, BACKUPTICS, NET_MAXPLAYERS};
 
 // Constants
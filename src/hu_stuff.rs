@@ -0,0 +1,205 @@
+//! Chat as Doom's heads-up display sends it: a message travels one character
+//! per tic in the `chatchar` field of the sender's ticcmds, starting with
+//! who it is for and ending with the enter key.
+
+use crate::net_structs::NET_MAXPLAYERS;
+
+/// Destination for a message to every player. Destinations from 1 up to
+/// this address the player with that number plus one.
+pub const HU_BROADCAST: u8 = 5;
+
+/// Key code that ends a message.
+pub const KEY_ENTER: u8 = 13;
+
+/// Longest message the chat input line holds.
+pub const HU_MAXLINELENGTH: usize = 80;
+
+/// Number of chat characters that can wait to be sent, as in Doom.
+pub const QUEUESIZE: usize = 128;
+
+/// Key code that deletes the last character typed.
+pub const KEY_BACKSPACE: u8 = 0x7f;
+
+/// Who a chat message is for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChatDestination {
+    All,
+    Player(u8),
+}
+
+/// A chat message another player finished typing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChatMessage {
+    pub sender: usize,
+    pub sender_name: String,
+    pub destination: ChatDestination,
+    pub text: String,
+}
+
+impl ChatDestination {
+    /// Returns the character that starts a message for this destination,
+    /// or `None` for a player Doom's chat cannot address.
+    fn chatchar(self) -> Option<u8> {
+        match self {
+            ChatDestination::All => Some(HU_BROADCAST),
+            ChatDestination::Player(player) if player < HU_BROADCAST - 1 => Some(player + 1),
+            ChatDestination::Player(_) => None,
+        }
+    }
+}
+
+/// Encodes a message as the characters to send, one per tic. Letters are
+/// sent in upper case, as Doom shifts them, and characters the HU font
+/// cannot show are left out. Returns `None` if the destination cannot be
+/// addressed.
+pub fn encode_message(dest: ChatDestination, text: &str) -> Option<Vec<u8>> {
+    let mut chars = vec![dest.chatchar()?];
+    chars.extend(text.chars().filter_map(chat_char).take(HU_MAXLINELENGTH));
+    chars.push(KEY_ENTER);
+    Some(chars)
+}
+
+/// The HU font only has the characters from ' ' to '_'.
+fn chat_char(c: char) -> Option<u8> {
+    let c = c.to_ascii_uppercase();
+    (' '..='_').contains(&c).then_some(c as u8)
+}
+
+/// Turns the chat characters in other players' ticcmds back into messages,
+/// the way HU_Ticker does.
+#[derive(Debug, Clone)]
+pub struct ChatReassembler {
+    destinations: [ChatDestination; NET_MAXPLAYERS],
+    lines: [String; NET_MAXPLAYERS],
+}
+
+impl Default for ChatReassembler {
+    fn default() -> Self {
+        ChatReassembler {
+            destinations: [ChatDestination::All; NET_MAXPLAYERS],
+            lines: Default::default(),
+        }
+    }
+}
+
+impl ChatReassembler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a character from `player`'s ticcmd, returning the message's
+    /// destination and text once the player presses enter.
+    pub fn feed(&mut self, player: usize, chatchar: u8) -> Option<(ChatDestination, String)> {
+        let line = &mut self.lines[player];
+
+        // Clients send the key as typed, which HU_Ticker shifts
+        match chatchar.to_ascii_uppercase() {
+            0 => {}
+            1..HU_BROADCAST => self.destinations[player] = ChatDestination::Player(chatchar - 1),
+            HU_BROADCAST => self.destinations[player] = ChatDestination::All,
+            c @ b' '..=b'_' => {
+                if line.len() < HU_MAXLINELENGTH {
+                    line.push(c as char);
+                }
+            }
+            KEY_BACKSPACE => {
+                line.pop();
+            }
+            KEY_ENTER => {
+                let text = std::mem::take(line);
+                if !text.is_empty() {
+                    return Some((self.destinations[player], text));
+                }
+            }
+            _ => {}
+        }
+
+        None
+    }
+
+    /// Forgets anything half typed.
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_message() {
+        let chars = encode_message(ChatDestination::All, "gg, no re").unwrap();
+        assert_eq!(chars[0], HU_BROADCAST);
+        assert_eq!(&chars[1..chars.len() - 1], b"GG, NO RE");
+        assert_eq!(chars.last(), Some(&KEY_ENTER));
+
+        let chars = encode_message(ChatDestination::Player(2), "hi").unwrap();
+        assert_eq!(chars, [3, b'H', b'I', KEY_ENTER]);
+    }
+
+    #[test]
+    fn test_reassemble_message() {
+        let mut chat = ChatReassembler::new();
+        let mut received = Vec::new();
+        let typed = [2, b'H', b'X', KEY_BACKSPACE, b'I', KEY_ENTER];
+        for &c in typed
+            .iter()
+            .chain(&encode_message(ChatDestination::All, "go").unwrap())
+        {
+            received.extend(chat.feed(3, c));
+            assert_eq!(chat.feed(4, 0), None);
+        }
+
+        assert_eq!(
+            received,
+            [
+                (ChatDestination::Player(1), "HI".to_string()),
+                (ChatDestination::All, "GO".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn test_reassemble_shifts_typed_keys() {
+        let mut chat = ChatReassembler::new();
+        let mut received = Vec::new();
+        for &c in [HU_BROADCAST].iter().chain(b"hi\r") {
+            received.extend(chat.feed(0, c));
+        }
+
+        assert_eq!(received, [(ChatDestination::All, "HI".to_string())]);
+    }
+
+    #[test]
+    fn test_reassemble_keeps_players_apart() {
+        let mut chat = ChatReassembler::new();
+        chat.feed(0, HU_BROADCAST);
+        chat.feed(1, 1);
+        chat.feed(0, b'A');
+        chat.feed(1, b'B');
+
+        assert_eq!(
+            chat.feed(1, KEY_ENTER),
+            Some((ChatDestination::Player(0), "B".to_string()))
+        );
+        assert_eq!(
+            chat.feed(0, KEY_ENTER),
+            Some((ChatDestination::All, "A".to_string()))
+        );
+
+        // Nothing typed, nothing said
+        assert_eq!(chat.feed(0, KEY_ENTER), None);
+    }
+
+    #[test]
+    fn test_encode_message_drops_what_doom_cannot_show() {
+        let chars = encode_message(ChatDestination::All, "a{b}\u{e9}c").unwrap();
+        assert_eq!(chars, [HU_BROADCAST, b'A', b'B', b'C', KEY_ENTER]);
+
+        let chars = encode_message(ChatDestination::All, &"x".repeat(100)).unwrap();
+        assert_eq!(chars.len(), HU_MAXLINELENGTH + 2);
+
+        assert_eq!(encode_message(ChatDestination::Player(4), "hi"), None);
+    }
+}
@@ -0,0 +1,13 @@
+pub mod bot;
+pub mod hu_stuff;
+pub mod net_client;
+pub mod net_common;
+pub mod net_impair;
+pub mod net_io;
+pub mod net_loop;
+pub mod net_master;
+pub mod net_packet;
+pub mod net_query;
+pub mod net_server;
+pub mod net_structrw;
+pub mod net_structs;
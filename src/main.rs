@@ -1,13 +1,13 @@
 #![allow(unused)]
 
-mod bot;
 mod d_loop;
-mod net_client;
-mod net_packet;
-mod net_structs;
 
 use std::net::SocketAddr;
-use tracing::info;
+use std::process;
+use std::time::Duration;
+use tracing::{error, info};
+
+use doom_bot_client::{net_client, net_io, net_query, net_server, net_structs};
 
 use self::net_client::NetClient;
 use self::net_structs::ConnectData;
@@ -15,13 +15,40 @@ use self::net_structs::ConnectData;
 fn main() {
     tracing_subscriber::fmt::init();
 
+    let args: Vec<String> = std::env::args().collect();
+    match args.get(1).map(String::as_str) {
+        Some("query") => {
+            run_query(args.get(2).map(String::as_str));
+            return;
+        }
+        Some("discover") => {
+            run_discover();
+            return;
+        }
+        _ => {}
+    }
+
+    // Without an explicit address, join the first compatible LAN server
+    let server_addr: SocketAddr = match args.get(1) {
+        Some(addr) => resolve_or_exit(addr),
+        None => find_lan_server().unwrap_or_else(|| {
+            error!("No compatible server found on the local network");
+            process::exit(1);
+        }),
+    };
+
     info!("Initializing client");
-    let mut client = NetClient::new("Player1".to_string(), false);
+    let mut client = NetClient::for_server("Player1".to_string(), false, server_addr)
+        .unwrap_or_else(|e| {
+            error!("Failed to create a socket for {}: {}", server_addr, e);
+            process::exit(1);
+        });
     client.init();
 
-    info!("Client initialized, attempting to connect");
-
-    let server_addr: SocketAddr = "127.0.0.1:2342".parse().expect("Invalid server address");
+    info!(
+        "Client initialized, attempting to connect to {}",
+        server_addr
+    );
 
     let connect_data = ConnectData {
         gamemode: 0,
@@ -35,26 +62,116 @@ fn main() {
         player_class: 0,
     };
 
-    if client.connect(server_addr, connect_data) {
-        info!("Connected to server, starting main loop");
+    match client.connect(server_addr, connect_data) {
+        Ok(connected) => {
+            info!(
+                "Connected to {} ({:?}), starting main loop",
+                connected.server_version, connected.protocol
+            );
 
-        // Initialize the game loop
-        d_loop::d_start_game_loop();
+            // Initialize the game loop
+            d_loop::d_start_game_loop();
 
-        loop {
-            // Run the network client
-            client.run();
+            loop {
+                // Run the network client
+                client.run();
 
-            // Run the game loop
-            d_loop::try_run_tics(&mut client);
+                // Run the game loop
+                d_loop::try_run_tics(&mut client);
 
-            // Update the network state
-            d_loop::net_update(&mut client);
+                // Update the network state
+                d_loop::net_update(&mut client);
 
-            // Add some delay to prevent busy-waiting
-            std::thread::sleep(std::time::Duration::from_millis(10));
+                // Add some delay to prevent busy-waiting
+                std::thread::sleep(std::time::Duration::from_millis(10));
+            }
         }
-    } else {
-        info!("Failed to connect to server");
+        Err(e) => error!("Failed to connect to server: {}", e),
     }
 }
+
+/// Queries a server and prints what it reports about itself.
+fn run_query(addr: Option<&str>) {
+    let Some(addr) = addr else {
+        eprintln!("Usage: doom_bot_client query <address>");
+        process::exit(1);
+    };
+
+    let server_addr = resolve_or_exit(addr);
+
+    match net_query::query(server_addr) {
+        Ok(data) => {
+            let state = if data.server_state == 0 {
+                "waiting"
+            } else {
+                "in game"
+            };
+
+            println!("Address:     {}", server_addr);
+            println!("Version:     {}", data.version);
+            println!("State:       {}", state);
+            println!("Players:     {}/{}", data.num_players, data.max_players);
+            println!("Mode:        {}", data.gamemode);
+            println!("Mission:     {}", data.gamemission);
+            println!("Description: {}", data.description);
+            println!("Protocol:    {:?}", data.protocol);
+        }
+        Err(e) => {
+            error!("Failed to query {}: {}", server_addr, e);
+            process::exit(1);
+        }
+    }
+}
+
+/// Resolves a server address given on the command line, exiting with an
+/// error if it cannot be resolved.
+fn resolve_or_exit(addr: &str) -> SocketAddr {
+    net_io::resolve_address(addr).unwrap_or_else(|e| {
+        error!("{}", e);
+        process::exit(1);
+    })
+}
+
+// Time to wait for servers on the local network to answer a broadcast
+const LAN_DISCOVERY_WINDOW: Duration = Duration::from_secs(2);
+
+/// Lists every server on the local network that answers a query broadcast.
+fn run_discover() {
+    match net_query::find_lan_servers(LAN_DISCOVERY_WINDOW) {
+        Ok(servers) => {
+            for server in servers {
+                println!(
+                    "{:<22} {:>4}ms  {}/{}  {}  {}",
+                    server.addr,
+                    server.rtt.as_millis(),
+                    server.data.num_players,
+                    server.data.max_players,
+                    server.data.version,
+                    server.data.description
+                );
+            }
+        }
+        Err(e) => {
+            error!("Failed to search the local network: {}", e);
+            process::exit(1);
+        }
+    }
+}
+
+/// Searches the local network for the first server we can join.
+fn find_lan_server() -> Option<SocketAddr> {
+    info!("Searching the local network for servers");
+
+    let servers = match net_query::find_lan_servers(LAN_DISCOVERY_WINDOW) {
+        Ok(servers) => servers,
+        Err(e) => {
+            error!("Failed to search the local network: {}", e);
+            return None;
+        }
+    };
+
+    servers
+        .into_iter()
+        .find(|server| server.is_compatible())
+        .map(|server| server.addr)
+}
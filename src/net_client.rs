@@ -1,16 +1,239 @@
-use std::net::{SocketAddr, UdpSocket};
+use std::collections::VecDeque;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::mpsc::{self, Receiver, Sender};
 use std::time::{Duration, Instant};
 
-use crate::{bot::*, net_packet::NetPacket, net_structs::*};
+use crate::hu_stuff::{self, ChatDestination, ChatMessage, ChatReassembler, QUEUESIZE};
+use crate::net_io::{self, Transport};
+use crate::net_packet::{FieldContext, NetPacket, PacketError};
+use crate::{bot::*, net_master, net_structs::*};
+
+/// Version we claim to be when speaking the legacy handshake, until a
+/// server tells us which version it wants.
+pub const LEGACY_VERSION: &str = "Chocolate Doom 2.3.0";
+
+/// How long `connect` waits for the server by default.
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Something the client learnt while running, for code embedding it to
+/// react to. See [`NetClient::subscribe`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum ClientEvent {
+    /// The server accepted our SYN
+    Connected {
+        server_version: String,
+        protocol: NetProtocol,
+    },
+    /// The server refused to let us in
+    Rejected(String),
+    /// The lobby changed
+    WaitingData(Box<NetWaitData>),
+    /// The game was launched and is waiting for the controller to start it
+    Launched { num_players: u8 },
+    /// The game started with these settings
+    GameStarted(GameSettings),
+    /// A message from the server console
+    ConsoleMessage(String),
+    /// The server asked us to send some of our tics again
+    ResendRequested { start: u32, num_tics: u8 },
+    /// How long our tics took to reach the server and back, next to how
+    /// late the server says they arrived
+    Latency { latency: i32, remote_latency: i32 },
+    /// Another player finished typing a chat message
+    Chat(ChatMessage),
+    /// The server's WAD or DEH checksums stopped matching ours
+    ChecksumMismatch(ChecksumMismatch),
+    /// The controller policy launched the game once enough players were
+    /// ready
+    AutoLaunched { ready_players: i32 },
+    /// The controller policy would have launched the game, but the
+    /// checksums differ and mismatched launches are refused. It does not
+    /// try again.
+    AutoLaunchRefused(ChecksumMismatch),
+    /// The controller policy started the game
+    AutoStarted,
+    /// The connection to the server is gone
+    Disconnected(Option<DisconnectReason>),
+}
+
+/// Our WAD and DEH checksums next to the ones the server sent, when they
+/// differ. Playing on would end in a desync.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChecksumMismatch {
+    pub local_wad_sha1sum: [u8; 20],
+    pub server_wad_sha1sum: [u8; 20],
+    pub local_deh_sha1sum: [u8; 20],
+    pub server_deh_sha1sum: [u8; 20],
+}
+
+impl ChecksumMismatch {
+    pub fn wad_differs(&self) -> bool {
+        self.local_wad_sha1sum != self.server_wad_sha1sum
+    }
+
+    pub fn deh_differs(&self) -> bool {
+        self.local_deh_sha1sum != self.server_deh_sha1sum
+    }
+}
+
+impl fmt::Display for ChecksumMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut mismatches = Vec::new();
+        if self.wad_differs() {
+            mismatches.push(("WAD", &self.local_wad_sha1sum, &self.server_wad_sha1sum));
+        }
+        if self.deh_differs() {
+            mismatches.push(("DEH", &self.local_deh_sha1sum, &self.server_deh_sha1sum));
+        }
+
+        for (i, (what, local, server)) in mismatches.into_iter().enumerate() {
+            if i > 0 {
+                write!(f, "; ")?;
+            }
+            write!(
+                f,
+                "{} checksum mismatch: ours is {}, the server's is {}",
+                what,
+                sha1_hex(local),
+                sha1_hex(server)
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::error::Error for ChecksumMismatch {}
+
+fn sha1_hex(sum: &[u8; 20]) -> String {
+    sum.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+impl fmt::Display for ClientEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientEvent::Connected {
+                server_version,
+                protocol,
+            } => write!(f, "Connected to '{}' using {:?}", server_version, protocol),
+            ClientEvent::Rejected(reason) => write!(f, "Connection refused: {}", reason),
+            ClientEvent::WaitingData(data) => write!(
+                f,
+                "Lobby has {}/{} players, {} ready",
+                data.num_players, data.max_players, data.ready_players
+            ),
+            ClientEvent::Launched { num_players } => write!(
+                f,
+                "Launched with {} players, now waiting to start the game",
+                num_players
+            ),
+            ClientEvent::GameStarted(settings) => {
+                write!(f, "Game started with {} players", settings.num_players)
+            }
+            ClientEvent::ConsoleMessage(msg) => write!(f, "Message from server:\n{}", msg),
+            ClientEvent::ResendRequested { start, num_tics } => {
+                write!(f, "Resend request: start={}, num_tics={}", start, num_tics)
+            }
+            ClientEvent::Latency {
+                latency,
+                remote_latency,
+            } => write!(f, "Latency {}, remote {}", latency, remote_latency),
+            ClientEvent::Chat(message) => write!(
+                f,
+                "{} ({:?}): {}",
+                message.sender_name, message.destination, message.text
+            ),
+            ClientEvent::ChecksumMismatch(mismatch) => write!(f, "Warning: {}", mismatch),
+            ClientEvent::AutoLaunched { ready_players } => {
+                write!(f, "{} players ready, launched the game", ready_players)
+            }
+            ClientEvent::AutoLaunchRefused(mismatch) => {
+                write!(f, "Not launching the game: {}", mismatch)
+            }
+            ClientEvent::AutoStarted => write!(f, "Started the game"),
+            ClientEvent::Disconnected(Some(DisconnectReason::Timeout)) => {
+                write!(f, "Connection to server timed out")
+            }
+            ClientEvent::Disconnected(Some(DisconnectReason::Remote)) => {
+                write!(f, "Disconnected by server")
+            }
+            ClientEvent::Disconnected(_) => write!(f, "Disconnected"),
+        }
+    }
+}
+
+/// What the server told us when it accepted our connection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectedInfo {
+    pub server_version: String,
+    pub protocol: NetProtocol,
+}
 
-const NET_MAGIC_NUMBER: u32 = 1454104972;
+/// Why [`NetClient::connect`] failed.
+#[derive(Debug)]
+pub enum ConnectError {
+    /// The server refused us, for the reason given
+    Rejected(String),
+    /// The server did not answer in time
+    Timeout,
+    /// The server runs a version we cannot play with, or we share no
+    /// protocol with it
+    VersionMismatch { server_version: String },
+    /// Sending to the server failed
+    Io(io::Error),
+    /// The server answered with a packet we could not read
+    InvalidReply(PacketError),
+}
+
+impl fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectError::Rejected(reason) => write!(f, "rejected by the server: {}", reason),
+            ConnectError::Timeout => write!(f, "no response from the server"),
+            ConnectError::VersionMismatch { server_version } => {
+                write!(f, "incompatible server version '{}'", server_version)
+            }
+            ConnectError::Io(e) => write!(f, "socket error: {}", e),
+            ConnectError::InvalidReply(e) => write!(f, "invalid reply from the server: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ConnectError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConnectError::Io(e) => Some(e),
+            ConnectError::InvalidReply(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// When a client that is the lobby controller launches and starts the
+/// game, for running matches unattended.
+#[derive(Debug, Clone, Default)]
+pub struct ControllerPolicy {
+    /// Launch once this many players are ready. The number of slots on the
+    /// server caps it, so a large number waits for a full lobby.
+    pub min_players: i32,
+    /// Settings to start the game with. The server fills in the number of
+    /// players and who is who.
+    pub settings: GameSettings,
+}
 
 pub struct NetClient {
-    socket: UdpSocket,
+    transport: Box<dyn Transport>,
+    // Whether `transport` is a UDP socket we bound ourselves, and so may
+    // rebind for the server's address family
+    owns_socket: bool,
     state: ClientState,
-    server_addr: SocketAddr,
+    connection: NetConnection,
     settings: Option<GameSettings>,
-    reject_reason: Option<String>,
+    connect_result: Option<Result<ConnectedInfo, ConnectError>>,
+    connect_timeout: Duration,
+    legacy_version: Option<String>,
     player_name: String,
     drone: bool,
     recv_window_start: u32,
@@ -19,6 +242,8 @@ pub struct NetClient {
     need_acknowledge: bool,
     gamedata_recv_time: Instant,
     last_latency: i32,
+    clock_cumul_error: i32,
+    clock_last_error: i32,
     net_local_wad_sha1sum: [u8; 20],
     net_local_deh_sha1sum: [u8; 20],
     net_local_is_freedoom: bool,
@@ -26,20 +251,56 @@ pub struct NetClient {
     net_client_connected: bool,
     net_client_received_wait_data: bool,
     net_client_wait_data: NetWaitData,
+    lobby: Option<LobbyState>,
+    checksum_mismatch: Option<ChecksumMismatch>,
+    refuse_mismatched_launch: bool,
     last_send_time: Instant,
+    master_addr: Option<SocketAddr>,
     last_ticcmd: TicCmd,
+    chat_queue: VecDeque<u8>,
+    chat: ChatReassembler,
+    subscribers: Vec<Sender<ClientEvent>>,
+    controller_policy: Option<ControllerPolicy>,
+    controller_acted: Option<ClientState>,
     recvwindow_cmd_base: Vec<TicCmd>,
     bot: Bot,
 }
 
 impl NetClient {
+    /// Creates a client with an IPv4 UDP socket. `connect` replaces it with
+    /// an IPv6 one if the server turns out to need it.
     pub fn new(player_name: String, drone: bool) -> Self {
+        let socket =
+            net_io::bind_udp("0.0.0.0:0".parse().unwrap()).expect("Failed to bind UDP socket");
+        let mut client = Self::with_transport(player_name, drone, Box::new(socket));
+        client.owns_socket = true;
+        client
+    }
+
+    /// Creates a client with a UDP socket of the right address family to
+    /// reach `server_addr`.
+    pub fn for_server(
+        player_name: String,
+        drone: bool,
+        server_addr: SocketAddr,
+    ) -> io::Result<Self> {
+        let socket = net_io::bind_udp_for(server_addr)?;
+        let mut client = Self::with_transport(player_name, drone, Box::new(socket));
+        client.owns_socket = true;
+        Ok(client)
+    }
+
+    /// Creates a client that talks to the server over `transport`.
+    pub fn with_transport(player_name: String, drone: bool, transport: Box<dyn Transport>) -> Self {
         NetClient {
-            socket: UdpSocket::bind("0.0.0.0:0").expect("Failed to bind UDP socket"),
+            transport,
+            owns_socket: false,
             state: ClientState::Disconnected,
-            server_addr: "127.0.0.1:2342".parse().unwrap(), // Placeholder
+            connection: NetConnection::new("127.0.0.1:2342".parse().unwrap()), // Placeholder
             settings: None,
-            reject_reason: None,
+            connect_result: None,
+            connect_timeout: CONNECT_TIMEOUT,
+            legacy_version: None,
             player_name,
             drone,
             recv_window_start: 0,
@@ -48,6 +309,8 @@ impl NetClient {
             need_acknowledge: false,
             gamedata_recv_time: Instant::now(),
             last_latency: 0,
+            clock_cumul_error: 0,
+            clock_last_error: 0,
             net_local_wad_sha1sum: [0; 20],
             net_local_deh_sha1sum: [0; 20],
             net_local_is_freedoom: false,
@@ -55,8 +318,17 @@ impl NetClient {
             net_client_connected: false,
             net_client_received_wait_data: false,
             net_client_wait_data: NetWaitData::default(),
+            lobby: None,
+            checksum_mismatch: None,
+            refuse_mismatched_launch: false,
             last_send_time: Instant::now(),
+            master_addr: None,
             last_ticcmd: TicCmd::default(),
+            chat_queue: VecDeque::new(),
+            chat: ChatReassembler::new(),
+            subscribers: Vec::new(),
+            controller_policy: None,
+            controller_acted: None,
             recvwindow_cmd_base: vec![TicCmd::default(); NET_MAXPLAYERS],
             bot: Bot::new(),
         }
@@ -70,6 +342,8 @@ impl NetClient {
         self.bot.init();
         self.net_client_connected = false;
         self.net_client_received_wait_data = false;
+        self.lobby = None;
+        self.checksum_mismatch = None;
         self.net_waiting_for_launch = false;
 
         // Try to set player name from environment variables or command line arguments
@@ -96,10 +370,29 @@ impl NetClient {
 
         self.receive_packets();
 
-        if self.state == ClientState::Disconnected {
-            self.handle_disconnected();
+        // Run the common connection code to send any packets as needed
+        self.connection.run(self.transport.as_ref());
+
+        match self.connection.state {
+            ConnectionState::DisconnectedSleep => {
+                // Keep answering stray disconnect packets until the
+                // connection code is done sleeping.
+                if self.state != ClientState::DisconnectedSleep {
+                    self.handle_disconnected();
+                    self.state = ClientState::DisconnectedSleep;
+                }
+            }
+            ConnectionState::Disconnected => {
+                if self.state != ClientState::DisconnectedSleep {
+                    self.handle_disconnected();
+                }
+                self.shutdown();
+            }
+            _ => {}
         }
 
+        self.run_controller();
+
         if self.state == ClientState::InGame {
             self.advance_window();
             self.check_resends();
@@ -109,14 +402,10 @@ impl NetClient {
     }
 
     fn receive_packets(&mut self) {
-        self.socket
-            .set_nonblocking(true)
-            .expect("Failed to set non-blocking");
-
         loop {
-            match NetPacket::receive(&self.socket) {
+            match NetPacket::receive(self.transport.as_ref()) {
                 Ok((mut packet, addr)) => {
-                    if addr == self.server_addr {
+                    if addr == self.connection.addr {
                         self.parse_packet(&mut packet);
                     }
                 }
@@ -129,232 +418,417 @@ impl NetClient {
                 }
             }
         }
+    }
 
-        self.socket
-            .set_nonblocking(false)
-            .expect("Failed to set blocking");
+    /// Launches and starts the game according to the controller policy,
+    /// if we are the controller.
+    fn run_controller(&mut self) {
+        let Some(policy) = &self.controller_policy else {
+            return;
+        };
+        let wait_data = &self.net_client_wait_data;
+        if !self.net_client_received_wait_data
+            || wait_data.is_controller == 0
+            || self.controller_acted == Some(self.state)
+        {
+            return;
+        }
+
+        match self.state {
+            ClientState::WaitingLaunch => {
+                let needed = policy.min_players.min(wait_data.max_players).max(1);
+                if wait_data.ready_players < needed {
+                    return;
+                }
+
+                let ready_players = wait_data.ready_players;
+                match self.launch_game() {
+                    Ok(()) => self.emit(ClientEvent::AutoLaunched { ready_players }),
+                    Err(mismatch) => self.emit(ClientEvent::AutoLaunchRefused(mismatch)),
+                }
+            }
+            ClientState::WaitingStart => {
+                let settings = policy.settings;
+                self.start_game(&settings);
+                self.emit(ClientEvent::AutoStarted);
+            }
+            _ => return,
+        }
+
+        self.controller_acted = Some(self.state);
     }
 
     fn handle_disconnected(&mut self) {
+        self.emit(ClientEvent::Disconnected(self.connection.disconnect_reason));
+
         self.receive_tic(
             &[TicCmd::default(); NET_MAXPLAYERS],
             &[false; NET_MAXPLAYERS],
         );
-        self.shutdown();
     }
 
     fn shutdown(&mut self) {
-        if self.net_client_connected {
-            self.send_disconnect();
-        }
         self.state = ClientState::Disconnected;
         self.net_client_connected = false;
     }
 
     fn parse_packet(&mut self, packet: &mut NetPacket) {
-        let packet_type = packet.read_u16().unwrap();
+        let mut packet_type = match packet.read_u16() {
+            Ok(packet_type) => packet_type,
+            Err(e) => {
+                println!("Client: Dropping malformed packet: {}", e);
+                return;
+            }
+        };
 
-        match NetPacketType::try_from(packet_type) {
-            Ok(NetPacketType::Syn) => self.parse_syn(packet),
-            Ok(NetPacketType::Rejected) => self.parse_reject(packet),
-            Ok(NetPacketType::WaitingData) => self.parse_waiting_data(packet),
-            Ok(NetPacketType::Launch) => self.parse_launch(packet),
-            Ok(NetPacketType::GameStart) => self.parse_game_start(packet),
-            Ok(NetPacketType::GameData) => self.parse_game_data(packet),
-            Ok(NetPacketType::GameDataResend) => self.parse_resend_request(packet),
-            Ok(NetPacketType::ConsoleMessage) => self.parse_console_message(packet),
-            _ => println!("Unknown packet type: {}", packet_type),
+        if self
+            .connection
+            .process_packet(self.transport.as_ref(), packet, &mut packet_type)
+        {
+            // Packet handled by the common connection code
+            return;
+        }
+
+        if self.connection.state == ConnectionState::DisconnectedSleep {
+            // Only the connection code still cares about the server
+            return;
+        }
+
+        let Ok(packet_type) = NetPacketType::try_from(packet_type) else {
+            println!("Unknown packet type: {}", packet_type);
+            return;
+        };
+
+        let result = match packet_type {
+            NetPacketType::Syn => self.parse_syn(packet),
+            NetPacketType::Rejected => self.parse_reject(packet),
+            NetPacketType::WaitingData => self.parse_waiting_data(packet),
+            NetPacketType::Launch => self.parse_launch(packet),
+            NetPacketType::GameStart => self.parse_game_start(packet),
+            NetPacketType::GameData => self.parse_game_data(packet),
+            NetPacketType::GameDataResend => self.parse_resend_request(packet),
+            NetPacketType::ConsoleMessage => self.parse_console_message(packet),
+            NetPacketType::NatHolePunch => {
+                // Only sent to open our NAT gateway; nothing to do.
+                Ok(())
+            }
+            _ => {
+                println!("Unknown packet type: {:?}", packet_type);
+                Ok(())
+            }
+        };
+
+        // A malformed packet is dropped without touching our state any
+        // further than the parser already has.
+        if let Err(e) = result {
+            println!("Client: Dropping malformed {:?} packet: {}", packet_type, e);
+
+            // A reply to our SYN that makes no sense ends the attempt to
+            // connect.
+            if self.connection.state == ConnectionState::Connecting
+                && matches!(packet_type, NetPacketType::Syn | NetPacketType::Rejected)
+            {
+                self.connection.state = ConnectionState::Disconnected;
+                self.connect_result = Some(Err(ConnectError::InvalidReply(e)));
+            }
         }
     }
 
-    fn parse_syn(&mut self, packet: &mut NetPacket) {
+    fn parse_syn(&mut self, packet: &mut NetPacket) -> Result<(), PacketError> {
         println!("Client: Processing SYN response");
-        let server_version = packet.read_string().unwrap_or_default();
+        let server_version = packet.read_string().field("server version")?;
 
-        println!("Client: Connected to server");
-        self.state = ClientState::WaitingLaunch;
+        // Servers before v3.0 do not negotiate a protocol; they all speak
+        // what became the first one.
+        let protocol = if self.legacy_version.is_some() {
+            NetProtocol::ChocolateDoom0
+        } else {
+            packet.read_protocol()
+        };
+        if protocol == NetProtocol::Unknown {
+            if self.connection.state == ConnectionState::Connecting {
+                self.connection.state = ConnectionState::Disconnected;
+                self.connection.disconnect_reason = Some(DisconnectReason::Remote);
+                self.connect_result = Some(Err(ConnectError::VersionMismatch {
+                    server_version: server_version.clone(),
+                }));
+                self.emit(ClientEvent::Rejected(
+                    "Server selected an unsupported protocol".to_string(),
+                ));
+            }
+            return Ok(());
+        }
 
-        if server_version != env!("CARGO_PKG_VERSION") {
+        self.connection.state = ConnectionState::Connected;
+        self.connection.protocol = protocol;
+        self.state = ClientState::WaitingLaunch;
+        self.connect_result = Some(Ok(ConnectedInfo {
+            server_version: server_version.clone(),
+            protocol,
+        }));
+        self.emit(ClientEvent::Connected {
+            server_version: server_version.clone(),
+            protocol,
+        });
+
+        let version = self
+            .legacy_version
+            .as_deref()
+            .unwrap_or(env!("CARGO_PKG_VERSION"));
+        if server_version != version {
             println!(
                 "Client: Warning: This is '{}', but the server is '{}'. \
                 It is possible that this mismatch may cause the game to desynchronize.",
-                env!("CARGO_PKG_VERSION"),
-                server_version
+                version, server_version
             );
         }
+
+        Ok(())
     }
 
-    fn parse_reject(&mut self, packet: &mut NetPacket) {
-        if let Some(msg) = packet.read_string() {
-            if self.state == ClientState::WaitingLaunch {
-                self.state = ClientState::Disconnected;
-                self.reject_reason = Some(msg);
+    fn parse_reject(&mut self, packet: &mut NetPacket) -> Result<(), PacketError> {
+        let msg = packet.read_string().field("reject reason")?;
+        if self.connection.state == ConnectionState::Connecting {
+            if self.retry_legacy_handshake(&msg) {
+                // Send the new SYN straight away
+                self.last_send_time = Instant::now() - Duration::from_secs(1);
+                return Ok(());
             }
+
+            self.connection.state = ConnectionState::Disconnected;
+            let error = match VersionReject::parse(&msg) {
+                Some(reject) => ConnectError::VersionMismatch {
+                    server_version: reject.server_version().to_string(),
+                },
+                None => ConnectError::Rejected(msg.clone()),
+            };
+            self.connect_result = Some(Err(error));
+            self.emit(ClientEvent::Rejected(msg));
         }
+        Ok(())
     }
 
-    fn parse_waiting_data(&mut self, packet: &mut NetPacket) {
-        if let Some(wait_data) = packet.read_wait_data() {
-            if wait_data.num_players > wait_data.max_players
-                || wait_data.ready_players > wait_data.num_players
-                || wait_data.max_players > NET_MAXPLAYERS as i32
-            {
-                return;
-            }
+    /// Works out from a reject whether the legacy handshake would succeed
+    /// if tried differently, and if so adjusts it.
+    fn retry_legacy_handshake(&mut self, reason: &str) -> bool {
+        let Some(version) = &self.legacy_version else {
+            return false;
+        };
 
-            if (wait_data.consoleplayer >= 0 && self.drone)
-                || (wait_data.consoleplayer < 0 && !self.drone)
-                || (wait_data.consoleplayer as usize >= wait_data.num_players as usize)
+        match VersionReject::parse(reason) {
+            // An old server wants exactly its own version, and tells us which.
+            Some(VersionReject::VersionMismatch { server_version })
+                if server_version != *version =>
             {
-                return;
+                println!(
+                    "Client: Server is '{}', retrying the legacy handshake as that version",
+                    server_version
+                );
+                self.legacy_version = Some(server_version);
+                true
             }
+            // A newer server refuses the old handshake outright.
+            Some(VersionReject::OldClient { .. }) => {
+                println!("Client: Server is too new for the legacy handshake, retrying");
+                self.legacy_version = None;
+                true
+            }
+            _ => false,
+        }
+    }
 
-            self.net_client_wait_data = wait_data;
-            self.net_client_received_wait_data = true;
+    fn parse_waiting_data(&mut self, packet: &mut NetPacket) -> Result<(), PacketError> {
+        let wait_data = packet.read_wait_data()?;
+        if wait_data.num_players > wait_data.max_players
+            || wait_data.ready_players > wait_data.num_players
+            || wait_data.max_players > NET_MAXPLAYERS as i32
+        {
+            return Ok(());
+        }
+
+        if (wait_data.consoleplayer >= 0 && self.drone)
+            || (wait_data.consoleplayer < 0 && !self.drone)
+            || (wait_data.consoleplayer as usize >= wait_data.num_players as usize)
+        {
+            return Ok(());
+        }
+
+        self.net_client_wait_data = wait_data;
+        self.net_client_received_wait_data = true;
+        self.lobby = Some(LobbyState::from(&wait_data));
+        self.emit(ClientEvent::WaitingData(Box::new(wait_data)));
+        self.check_checksums();
+
+        Ok(())
+    }
+
+    /// Compares the server's checksums with ours, reporting a mismatch
+    /// when it first shows up or changes.
+    fn check_checksums(&mut self) {
+        let wait_data = &self.net_client_wait_data;
+        let mismatch = ChecksumMismatch {
+            local_wad_sha1sum: self.net_local_wad_sha1sum,
+            server_wad_sha1sum: wait_data.wad_sha1sum,
+            local_deh_sha1sum: self.net_local_deh_sha1sum,
+            server_deh_sha1sum: wait_data.deh_sha1sum,
+        };
+        let mismatch = (mismatch.wad_differs() || mismatch.deh_differs()).then_some(mismatch);
+
+        if mismatch != self.checksum_mismatch {
+            if let Some(mismatch) = mismatch {
+                self.emit(ClientEvent::ChecksumMismatch(mismatch));
+            }
+            self.checksum_mismatch = mismatch;
         }
     }
 
-    fn parse_launch(&mut self, packet: &mut NetPacket) {
+    fn parse_launch(&mut self, packet: &mut NetPacket) -> Result<(), PacketError> {
         println!("Client: Processing launch packet");
         if self.state != ClientState::WaitingLaunch {
             println!("Client: Error: Not in waiting launch state");
-            return;
+            return Ok(());
         }
 
-        if let Some(num_players) = packet.read_u8() {
-            self.net_client_wait_data.num_players = num_players as i32;
-            self.state = ClientState::WaitingStart;
-            println!("Client: Now waiting to start the game");
-        }
+        let num_players = packet.read_u8().field("launch num_players")?;
+        self.net_client_wait_data.num_players = num_players as i32;
+        self.state = ClientState::WaitingStart;
+        self.emit(ClientEvent::Launched { num_players });
+
+        Ok(())
     }
 
-    fn parse_game_start(&mut self, packet: &mut NetPacket) {
+    fn parse_game_start(&mut self, packet: &mut NetPacket) -> Result<(), PacketError> {
         println!("Client: Processing game start packet");
-        if let Some(settings) = packet.read_settings() {
-            if self.state != ClientState::WaitingStart {
-                println!("Client: Error: Not in waiting start state");
-                return;
-            }
-
-            if settings.num_players > NET_MAXPLAYERS as i32
-                || settings.consoleplayer as usize >= settings.num_players as usize
-            {
-                println!(
-                    "Client: Error: Invalid settings, num_players={}, consoleplayer={}",
-                    settings.num_players, settings.consoleplayer
-                );
-                return;
-            }
+        let settings = packet.read_settings_and_seed()?;
+        if self.state != ClientState::WaitingStart {
+            println!("Client: Error: Not in waiting start state");
+            return Ok(());
+        }
 
-            if (self.drone && settings.consoleplayer >= 0)
-                || (!self.drone && settings.consoleplayer < 0)
-            {
-                println!(
-                    "Client: Error: Mismatch: drone={}, consoleplayer={}",
-                    self.drone, settings.consoleplayer
-                );
-                return;
-            }
+        if settings.num_players > NET_MAXPLAYERS as i32
+            || settings.consoleplayer as usize >= settings.num_players as usize
+        {
+            println!(
+                "Client: Error: Invalid settings, num_players={}, consoleplayer={}",
+                settings.num_players, settings.consoleplayer
+            );
+            return Ok(());
+        }
 
-            println!("Client: Initiating game state");
-            self.state = ClientState::InGame;
-            self.settings = Some(settings);
-            self.recv_window_start = 0;
-            self.recv_window = vec![NetServerRecv::default(); BACKUPTICS];
-            self.send_queue = vec![NetServerSend::default(); BACKUPTICS];
+        if (self.drone && settings.consoleplayer >= 0)
+            || (!self.drone && settings.consoleplayer < 0)
+        {
+            println!(
+                "Client: Error: Mismatch: drone={}, consoleplayer={}",
+                self.drone, settings.consoleplayer
+            );
+            return Ok(());
         }
+
+        println!("Client: Initiating game state");
+        self.state = ClientState::InGame;
+        self.settings = Some(settings);
+        self.recv_window_start = 0;
+        self.recv_window = vec![NetServerRecv::default(); BACKUPTICS];
+        self.send_queue = vec![NetServerSend::default(); BACKUPTICS];
+        self.chat.reset();
+        self.emit(ClientEvent::GameStarted(settings));
+
+        Ok(())
     }
 
-    fn parse_game_data(&mut self, packet: &mut NetPacket) {
+    fn parse_game_data(&mut self, packet: &mut NetPacket) -> Result<(), PacketError> {
         println!("Client: Processing game data packet");
 
-        if let (Some(seq), Some(num_tics)) = (packet.read_u8(), packet.read_u8()) {
-            let seq = self.expand_tic_num(seq as u32);
-            println!(
-                "Client: Game data received, seq={}, num_tics={}",
-                seq, num_tics
-            );
+        let seq = packet.read_u8().field("gamedata seq")?;
+        let num_tics = packet.read_u8().field("gamedata num_tics")?;
 
-            let lowres_turn = self.settings.as_ref().unwrap().lowres_turn != 0;
-
-            for i in 0..num_tics {
-                if let Some(cmd) = packet.read_full_ticcmd(lowres_turn) {
-                    let index = (seq + i as u32 - self.recv_window_start) as usize;
-                    if index < BACKUPTICS {
-                        self.recv_window[index].active = true;
-                        self.recv_window[index].cmd = cmd;
-                        println!("Client: Stored tic {} in receive window", seq + i as u32);
-                        if i == num_tics - 1 {
-                            self.update_clock_sync(seq + i as u32, cmd.latency);
-                        }
-                    }
+        let Some(settings) = self.settings.as_ref() else {
+            println!("Client: Error: Game data received before the game started");
+            return Ok(());
+        };
+        let lowres_turn = settings.lowres_turn != 0;
+
+        let seq = self.expand_tic_num(seq as u32);
+        println!(
+            "Client: Game data received, seq={}, num_tics={}",
+            seq, num_tics
+        );
+
+        for i in 0..num_tics {
+            let cmd = packet.read_full_ticcmd(lowres_turn)?;
+            let tic = seq.wrapping_add(i as u32);
+
+            // Tics outside the receive window are dropped
+            let index = tic.wrapping_sub(self.recv_window_start) as usize;
+            if index < BACKUPTICS {
+                self.recv_window[index].active = true;
+                self.recv_window[index].cmd = cmd;
+                println!("Client: Stored tic {} in receive window", tic);
+                if i == num_tics - 1 {
+                    self.update_clock_sync(tic, cmd.latency);
                 }
             }
+        }
 
-            self.need_acknowledge = true;
-            self.gamedata_recv_time = Instant::now();
+        self.need_acknowledge = true;
+        self.gamedata_recv_time = Instant::now();
 
-            // Check for missing tics and request resends
-            let resend_end = seq as i32 - self.recv_window_start as i32;
-            if resend_end > 0 {
-                let mut resend_start = resend_end - 1;
-                while resend_start >= 0 && !self.recv_window[resend_start as usize].active {
-                    resend_start -= 1;
-                }
-                if resend_start < resend_end - 1 {
-                    self.send_resend_request(
-                        self.recv_window_start + resend_start as u32 + 1,
-                        self.recv_window_start + resend_end as u32 - 1,
-                    );
-                }
+        // Check for missing tics and request resends
+        let resend_end =
+            (seq.wrapping_sub(self.recv_window_start) as i32).min(BACKUPTICS as i32 - 1);
+        if resend_end > 0 {
+            let mut resend_start = resend_end - 1;
+            while resend_start >= 0 && !self.recv_window[resend_start as usize].active {
+                resend_start -= 1;
+            }
+            if resend_start < resend_end - 1 {
+                self.send_resend_request(
+                    self.recv_window_start + (resend_start + 1) as u32,
+                    self.recv_window_start + (resend_end - 1) as u32,
+                );
             }
         }
+
+        Ok(())
     }
 
-    fn parse_resend_request(&mut self, packet: &mut NetPacket) {
+    fn parse_resend_request(&mut self, packet: &mut NetPacket) -> Result<(), PacketError> {
         println!("Client: Processing resend request");
         if self.drone {
             println!("Client: Error: Resend request but we are a drone");
-            return;
+            return Ok(());
         }
 
-        if let (Some(start), Some(num_tics)) = (packet.read_i32(), packet.read_u8()) {
-            let end = start + num_tics as i32 - 1;
-            println!(
-                "Client: Resend request: start={}, num_tics={}",
-                start, num_tics
-            );
-
-            let mut resend_start = start as u32;
-            let mut resend_end = end as u32;
+        let start = packet.read_i32().field("resend start")? as u32;
+        let num_tics = packet.read_u8().field("resend num_tics")?;
+        self.emit(ClientEvent::ResendRequested { start, num_tics });
 
-            while resend_start <= resend_end
-                && (!self.send_queue[resend_start as usize % BACKUPTICS].active
-                    || self.send_queue[resend_start as usize % BACKUPTICS].seq != resend_start)
-            {
-                resend_start += 1;
-            }
-
-            while resend_start <= resend_end
-                && (!self.send_queue[resend_end as usize % BACKUPTICS].active
-                    || self.send_queue[resend_end as usize % BACKUPTICS].seq != resend_end)
-            {
-                resend_end -= 1;
-            }
+        // Check we have the tics being requested. If not, reduce the
+        // window of tics to only what we have.
+        let have_tic = |tic: u32| {
+            let send_obj = &self.send_queue[tic as usize % BACKUPTICS];
+            send_obj.active && send_obj.seq == tic
+        };
+        let requested = (0..num_tics as u32).map(|i| start.wrapping_add(i));
+        let resend_start = requested.clone().find(|&tic| have_tic(tic));
+        let resend_end = requested.rev().find(|&tic| have_tic(tic));
 
-            if resend_start <= resend_end {
-                println!("Client: Resending tics {}-{}", resend_start, resend_end);
-                self.send_tics(resend_start, resend_end);
-            } else {
-                println!("Client: Don't have the tics to resend");
-            }
+        if let (Some(resend_start), Some(resend_end)) = (resend_start, resend_end) {
+            println!("Client: Resending tics {}-{}", resend_start, resend_end);
+            self.send_tics(resend_start, resend_end);
+        } else {
+            println!("Client: Don't have the tics to resend");
         }
+
+        Ok(())
     }
 
-    fn parse_console_message(&self, packet: &mut NetPacket) {
-        if let Some(msg) = packet.read_string() {
-            println!("Message from server:\n{}", msg);
-        }
+    fn parse_console_message(&mut self, packet: &mut NetPacket) -> Result<(), PacketError> {
+        let msg = packet.read_string().field("console message")?;
+        self.emit(ClientEvent::ConsoleMessage(msg));
+
+        Ok(())
     }
 
     fn expand_tic_num(&self, b: u32) -> u32 {
@@ -383,21 +857,23 @@ impl NetClient {
             .as_millis() as i32;
         let error = latency - remote_latency;
 
-        // Update PID variables (these should be stored in the struct)
-        let mut cumul_error = 0;
-        let mut last_error = 0;
-
-        cumul_error += error;
-        let offset_ms =
-            (KP * error as f32 - KI * cumul_error as f32 + KD * (last_error - error) as f32) as i32;
+        // Update PID variables
+        self.clock_cumul_error = self.clock_cumul_error.saturating_add(error);
+        let cumul_error = self.clock_cumul_error;
+        let offset_ms = (KP * error as f32 - KI * cumul_error as f32
+            + KD * (self.clock_last_error - error) as f32) as i32;
 
-        last_error = error;
+        self.clock_last_error = error;
         self.last_latency = latency;
 
         println!(
-            "Client: Latency {}, remote {}, offset={}ms, cumul_error={}",
-            latency, remote_latency, offset_ms, cumul_error
+            "Client: Clock offset={}ms, cumul_error={}",
+            offset_ms, cumul_error
         );
+        self.emit(ClientEvent::Latency {
+            latency,
+            remote_latency,
+        });
     }
 
     fn send_resend_request(&mut self, start: u32, end: u32) {
@@ -406,15 +882,14 @@ impl NetClient {
         packet.write_i32(start as i32);
         packet.write_u8((end - start + 1) as u8);
 
-        packet
-            .send(&self.socket, &self.server_addr)
-            .expect("Failed to send packet");
+        self.connection
+            .send_or_log(self.transport.as_ref(), &packet, "resend request");
 
         let now = Instant::now();
         for i in start..=end {
             let index = (i - self.recv_window_start) as usize;
             if index < BACKUPTICS {
-                self.recv_window[index].resend_time = now;
+                self.recv_window[index].resend_time = Some(now);
             }
         }
     }
@@ -424,9 +899,8 @@ impl NetClient {
         packet.write_u16(NetPacketType::GameDataAck as u16);
         packet.write_u8((self.recv_window_start & 0xff) as u8);
 
-        packet
-            .send(&self.socket, &self.server_addr)
-            .expect("Failed to send packet");
+        self.connection
+            .send_or_log(self.transport.as_ref(), &packet, "game data ack");
         self.need_acknowledge = false;
         println!("Client: Game data acknowledgment sent");
     }
@@ -440,11 +914,11 @@ impl NetClient {
         packet.write_u16(NetPacketType::GameData as u16);
         packet.write_u8((self.recv_window_start & 0xff) as u8);
         packet.write_u8((start & 0xff) as u8);
-        packet.write_u8(((end - start + 1) & 0xff) as u8);
+        packet.write_u8((end.wrapping_sub(start).wrapping_add(1) & 0xff) as u8);
 
         for tic in start..=end {
             if let Some(send_obj) = self.send_queue.get(tic as usize % BACKUPTICS) {
-                packet.write_i16(self.last_latency.try_into().unwrap());
+                packet.write_i16(self.last_latency.clamp(i16::MIN as i32, i16::MAX as i32) as i16);
                 packet.write_ticcmd_diff(
                     &send_obj.cmd,
                     self.settings.as_ref().unwrap().lowres_turn != 0,
@@ -452,16 +926,21 @@ impl NetClient {
             }
         }
 
-        packet
-            .send(&self.socket, &self.server_addr)
-            .expect("Failed to send packet");
+        self.connection
+            .send_or_log(self.transport.as_ref(), &packet, "game data");
         self.need_acknowledge = false;
         println!("Client: Sent tics from {} to {}", start, end);
     }
 
     pub fn send_ticcmd(&mut self, ticcmd: &TicCmd, maketic: u32) {
+        // Chat goes out a character at a time, as G_BuildTiccmd does it
+        let mut ticcmd = *ticcmd;
+        if ticcmd.chatchar == 0 {
+            ticcmd.chatchar = self.chat_queue.pop_front().unwrap_or(0);
+        }
+
         let mut diff = NetTicDiff::default();
-        self.calculate_ticcmd_diff(ticcmd, &mut diff);
+        self.calculate_ticcmd_diff(&ticcmd, &mut diff);
 
         let sendobj = &mut self.send_queue[maketic as usize % BACKUPTICS];
         sendobj.active = true;
@@ -525,13 +1004,12 @@ impl NetClient {
     fn advance_window(&mut self) {
         while self.recv_window[0].active {
             let mut ticcmds = [TicCmd::default(); NET_MAXPLAYERS];
-            let window_start = self.recv_window_start;
 
             let window = self.recv_window[0].cmd;
-            self.expand_full_ticcmd(&window, window_start, &mut ticcmds);
+            self.expand_full_ticcmd(&window, &mut ticcmds);
 
             // Call D_ReceiveTic or equivalent game state update function
-            self.receive_tic(&ticcmds, &self.recv_window[0].cmd.playeringame);
+            self.receive_tic(&ticcmds, &window.playeringame);
 
             // Shift the window
             self.recv_window.rotate_left(1);
@@ -545,12 +1023,7 @@ impl NetClient {
         }
     }
 
-    fn expand_full_ticcmd(
-        &mut self,
-        cmd: &NetFullTicCmd,
-        seq: u32,
-        ticcmds: &mut [TicCmd; NET_MAXPLAYERS],
-    ) {
+    fn expand_full_ticcmd(&mut self, cmd: &NetFullTicCmd, ticcmds: &mut [TicCmd; NET_MAXPLAYERS]) {
         let consoleplayer = self.settings.as_ref().unwrap().consoleplayer as usize;
         let drone = self.drone;
         let mut recvwindow_cmd_base = self.recvwindow_cmd_base.clone();
@@ -611,7 +1084,7 @@ impl NetClient {
     }
 
     fn receive_tic(
-        &self,
+        &mut self,
         ticcmds: &[TicCmd; NET_MAXPLAYERS],
         playeringame: &[bool; NET_MAXPLAYERS],
     ) {
@@ -620,6 +1093,26 @@ impl NetClient {
             "Client: Received tic data for {} players",
             playeringame.iter().filter(|&&p| p).count()
         );
+
+        for (player, cmd) in ticcmds.iter().enumerate() {
+            if playeringame[player] {
+                self.receive_chat(player, cmd.chatchar);
+            }
+        }
+    }
+
+    fn receive_chat(&mut self, player: usize, chatchar: u8) {
+        let Some((destination, text)) = self.chat.feed(player, chatchar) else {
+            return;
+        };
+
+        let sender_name = player_string_to_string(&self.net_client_wait_data.player_names[player]);
+        self.emit(ClientEvent::Chat(ChatMessage {
+            sender: player,
+            sender_name,
+            destination,
+            text,
+        }));
     }
 
     fn check_resends(&mut self) {
@@ -629,17 +1122,16 @@ impl NetClient {
         let maybe_deadlocked = now.duration_since(self.gamedata_recv_time) > Duration::from_secs(1);
 
         for i in 0..BACKUPTICS {
-            let recvobj = &mut self.recv_window[i];
-            let need_resend =
-                !recvobj.active && recvobj.resend_time.elapsed() > Duration::from_millis(300);
-
-            if i == 0
-                && !recvobj.active
-                && recvobj.resend_time.elapsed() > Duration::from_secs(1)
-                && maybe_deadlocked
-            {
-                let need_resend = true;
-            }
+            let recvobj = &self.recv_window[i];
+
+            // Only tics we already asked for are asked for again, except
+            // for the first one: if it is missing and nothing has arrived
+            // for a while, the packet carrying it was probably lost.
+            let need_resend = !recvobj.active
+                && match recvobj.resend_time {
+                    Some(time) => time.elapsed() > Duration::from_millis(300),
+                    None => i == 0 && maybe_deadlocked,
+                };
 
             if need_resend {
                 if resend_start < 0 {
@@ -700,11 +1192,14 @@ impl NetClient {
         }
 
         println!("Client: Beginning disconnect");
-        self.send_disconnect();
+        self.connection.disconnect();
 
         let start_time = Instant::now();
 
-        while self.state != ClientState::Disconnected {
+        while !matches!(
+            self.connection.state,
+            ConnectionState::Disconnected | ConnectionState::DisconnectedSleep
+        ) {
             if start_time.elapsed() > Duration::from_secs(5) {
                 println!("Client: No acknowledgment of disconnect received");
                 self.state = ClientState::WaitingStart;
@@ -720,44 +1215,145 @@ impl NetClient {
         self.shutdown();
     }
 
-    fn send_disconnect(&self) {
-        let mut packet = NetPacket::new();
-        packet.write_u16(NetPacketType::Disconnect as u16);
-        packet
-            .send(&self.socket, &self.server_addr)
-            .expect("Failed to send disconnect packet");
+    /// Returns why the connection to the server was terminated, if it was.
+    pub fn disconnect_reason(&self) -> Option<DisconnectReason> {
+        self.connection.disconnect_reason
+    }
+
+    /// Returns the number of tics received from the server so far, which
+    /// is how far the game can run.
+    pub fn recv_tic(&self) -> u32 {
+        self.recv_window_start
+    }
+
+    /// Returns the lobby as the server last described it, or `None` until
+    /// the server has sent it.
+    pub fn lobby(&self) -> Option<&LobbyState> {
+        self.lobby.as_ref()
     }
 
     pub fn get_settings(&self) -> Option<GameSettings> {
         if self.state != ClientState::InGame {
             return None;
         }
-        self.settings.clone()
+        self.settings
     }
 
-    pub fn launch_game(&mut self) {
-        let mut packet = NetPacket::new();
-        packet.write_u16(NetPacketType::Launch as u16);
-        packet
-            .send(&self.socket, &self.server_addr)
-            .expect("Failed to send launch packet");
+    /// Asks the server to launch the game. Fails without sending anything
+    /// if the checksums differ and mismatched launches are refused.
+    pub fn launch_game(&mut self) -> Result<(), ChecksumMismatch> {
+        if let Some(mismatch) = self.checksum_mismatch {
+            if self.refuse_mismatched_launch {
+                return Err(mismatch);
+            }
+        }
+
+        self.connection.new_reliable(NetPacketType::Launch);
+        Ok(())
+    }
+
+    /// Returns how the server's WAD and DEH checksums differ from ours, as
+    /// of the last lobby update.
+    pub fn checksum_mismatch(&self) -> Option<&ChecksumMismatch> {
+        self.checksum_mismatch.as_ref()
+    }
+
+    /// Makes `launch_game` refuse to launch while the server's checksums
+    /// differ from ours.
+    pub fn set_refuse_mismatched_launch(&mut self, refuse: bool) {
+        self.refuse_mismatched_launch = refuse;
     }
 
     pub fn start_game(&mut self, settings: &GameSettings) {
         self.last_ticcmd = TicCmd::default();
 
-        let mut packet = NetPacket::new();
-        packet.write_u16(NetPacketType::GameStart as u16);
+        let packet = self.connection.new_reliable(NetPacketType::GameStart);
         packet.write_settings(settings);
-        packet
-            .send(&self.socket, &self.server_addr)
-            .expect("Failed to send game start packet");
     }
 
-    pub fn connect(&mut self, addr: SocketAddr, connect_data: ConnectData) -> bool {
-        self.server_addr = addr;
+    /// Returns a channel that receives every event from now on. Events
+    /// are produced as the client runs, so drain the receiver with
+    /// `try_iter` between calls to `run`.
+    pub fn subscribe(&mut self) -> Receiver<ClientEvent> {
+        let (sender, receiver) = mpsc::channel();
+        self.subscribers.push(sender);
+        receiver
+    }
+
+    /// Logs `event` and hands it to every subscriber.
+    fn emit(&mut self, event: ClientEvent) {
+        println!("Client: {}", event);
+
+        // Subscribers that dropped their receiver are forgotten
+        self.subscribers
+            .retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+
+    /// Says `text` to every player, a character per ticcmd sent from now
+    /// on. Returns false if the message does not fit in the chat queue.
+    pub fn say(&mut self, text: &str) -> bool {
+        self.queue_chat(ChatDestination::All, text)
+    }
+
+    /// Says `text` to `player` alone. Returns false if the message does
+    /// not fit in the chat queue, or if Doom's chat cannot address the
+    /// player: only the first four can be.
+    pub fn say_to(&mut self, player: u8, text: &str) -> bool {
+        self.queue_chat(ChatDestination::Player(player), text)
+    }
+
+    fn queue_chat(&mut self, dest: ChatDestination, text: &str) -> bool {
+        let Some(chars) = hu_stuff::encode_message(dest, text) else {
+            return false;
+        };
+
+        if self.chat_queue.len() + chars.len() > QUEUESIZE {
+            println!("Client: Chat queue full, '{}' unsent", text);
+            return false;
+        }
+
+        self.chat_queue.extend(chars);
+        true
+    }
+
+    /// Connects with the handshake of Chocolate Doom versions before v3.0,
+    /// for servers that do not understand the current one. Those servers
+    /// only accept clients of their exact version, which we learn from
+    /// their reject and retry with; a newer server refusing the old
+    /// handshake makes us retry with the current one.
+    pub fn set_legacy_handshake(&mut self, enabled: bool) {
+        self.legacy_version = enabled.then(|| LEGACY_VERSION.to_string());
+    }
+
+    /// Sets when to launch and start the game if we end up the lobby
+    /// controller; `None` leaves it to calls to `launch_game` and
+    /// `start_game`.
+    pub fn set_controller_policy(&mut self, policy: Option<ControllerPolicy>) {
+        self.controller_policy = policy;
+    }
+
+    /// Sets the master server used to request NAT hole punching when
+    /// connecting, for servers behind a NAT gateway.
+    pub fn set_master_server(&mut self, master_addr: Option<SocketAddr>) {
+        self.master_addr = master_addr;
+    }
+
+    /// Sets how long `connect` waits for the server before giving up.
+    pub fn set_connect_timeout(&mut self, timeout: Duration) {
+        self.connect_timeout = timeout;
+    }
+
+    /// Connects to the server at `addr`, waiting until it accepts or
+    /// refuses us or the connect timeout runs out.
+    pub fn connect(
+        &mut self,
+        addr: SocketAddr,
+        connect_data: ConnectData,
+    ) -> Result<ConnectedInfo, ConnectError> {
+        self.connection = NetConnection::new(addr);
+        self.connection.state = ConnectionState::Connecting;
         self.state = ClientState::Disconnected;
-        self.reject_reason = Some("Unknown reason".to_string());
+        self.connect_result = None;
 
         self.net_local_wad_sha1sum
             .copy_from_slice(&connect_data.wad_sha1sum);
@@ -767,66 +1363,761 @@ impl NetClient {
 
         self.net_client_connected = true;
         self.net_client_received_wait_data = false;
+        self.lobby = None;
+        self.checksum_mismatch = None;
+        self.controller_acted = None;
+
+        let result = self.try_connect(addr, &connect_data);
+
+        match &result {
+            Ok(_) => {
+                println!("Client: Successfully connected");
+                self.drone = connect_data.drone != 0;
+            }
+            Err(e) => {
+                println!("Client: Connection failed: {}", e);
+                self.shutdown();
+            }
+        }
+
+        result
+    }
+
+    fn try_connect(
+        &mut self,
+        addr: SocketAddr,
+        connect_data: &ConnectData,
+    ) -> Result<ConnectedInfo, ConnectError> {
+        let local_addr = self.transport.local_addr().map_err(ConnectError::Io)?;
+        if self.owns_socket && local_addr.is_ipv4() != addr.is_ipv4() {
+            let socket = net_io::bind_udp_for(addr).map_err(ConnectError::Io)?;
+            self.transport = Box::new(socket);
+        }
+
+        if let Some(master_addr) = self.master_addr {
+            println!("Client: Requesting NAT hole punch via {}", master_addr);
+            net_master::request_hole_punch(self.transport.as_ref(), master_addr, addr)
+                .map_err(ConnectError::Io)?;
+        }
 
         let start_time = Instant::now();
         self.last_send_time = Instant::now() - Duration::from_secs(1);
 
-        while self.state == ClientState::Disconnected {
+        while self.connection.state == ConnectionState::Connecting {
             let now = Instant::now();
 
             if now.duration_since(self.last_send_time) > Duration::from_secs(1) {
-                self.send_syn(&connect_data);
+                if self.master_addr.is_some() {
+                    self.send_hole_punch().map_err(ConnectError::Io)?;
+                }
+                self.send_syn(connect_data).map_err(ConnectError::Io)?;
                 self.last_send_time = now;
             }
 
-            if now.duration_since(start_time) > Duration::from_secs(120) {
-                self.reject_reason = Some("No response from server".to_string());
-                break;
+            if now.duration_since(start_time) > self.connect_timeout {
+                return Err(ConnectError::Timeout);
             }
 
             self.run();
             std::thread::sleep(Duration::from_millis(1));
         }
 
-        if self.state == ClientState::WaitingLaunch {
-            println!("Client: Successfully connected");
-
-            self.reject_reason = None;
-            self.drone = connect_data.drone != 0;
-
-            true
-        } else {
-            println!("Client: Connection failed");
+        self.connect_result
+            .take()
+            .unwrap_or_else(|| Err(ConnectError::Rejected("Unknown reason".to_string())))
+    }
 
-            self.shutdown();
-            false
-        }
+    /// Sends a hole punch packet to the server, so our NAT gateway lets its
+    /// replies through.
+    fn send_hole_punch(&mut self) -> io::Result<()> {
+        let mut packet = NetPacket::new();
+        packet.write_u16(NetPacketType::NatHolePunch as u16);
+        self.connection
+            .send_packet(self.transport.as_ref(), &packet)
+            .map(|_| ())
     }
 
-    fn send_syn(&self, data: &ConnectData) {
+    fn send_syn(&mut self, data: &ConnectData) -> io::Result<()> {
         let mut packet = NetPacket::new();
 
         packet.write_u16(NetPacketType::Syn as u16);
-        packet.write_u32(NET_MAGIC_NUMBER);
-        packet.write_string(env!("CARGO_PKG_VERSION"));
+        match &self.legacy_version {
+            Some(version) => {
+                packet.write_u32(NET_OLD_MAGIC_NUMBER);
+                packet.write_string(version);
+            }
+            None => {
+                packet.write_u32(NET_MAGIC_NUMBER);
+                packet.write_string(env!("CARGO_PKG_VERSION"));
+                packet.write_protocol_list();
+            }
+        }
         packet.write_connect_data(data);
         packet.write_string(&self.player_name);
 
-        packet
-            .send(&self.socket, &self.server_addr)
-            .expect("Failed to send SYN packet");
+        self.connection
+            .send_packet(self.transport.as_ref(), &packet)?;
         println!("Client: SYN sent");
+        Ok(())
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use proptest::prelude::*;
+    use std::net::UdpSocket;
 
     #[test]
     fn test_client_initialization() {
         let client = NetClient::new("Player1".to_string(), false);
         assert_eq!(client.player_name, "Player1");
-        assert_eq!(client.drone, false);
+        assert!(!client.drone);
+    }
+
+    #[test]
+    fn test_connect_punches_hole_before_syn() {
+        let master = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let master_addr = master.local_addr().unwrap();
+        let server_addr = server.local_addr().unwrap();
+
+        let server_thread = std::thread::spawn(move || {
+            let (mut punch, _) = NetPacket::receive(&server).unwrap();
+            assert_eq!(punch.read_u16(), Ok(NetPacketType::NatHolePunch as u16));
+
+            let (mut syn, src) = NetPacket::receive(&server).unwrap();
+            assert_eq!(syn.read_u16(), Ok(NetPacketType::Syn as u16));
+
+            let mut reject = NetPacket::new();
+            reject.write_u16(NetPacketType::Rejected as u16);
+            reject.write_string("Server is full");
+            reject.send(&server, &src).unwrap();
+        });
+
+        let mut client = NetClient::new("Player1".to_string(), false);
+        client.set_master_server(Some(master_addr));
+        let result = client.connect(server_addr, ConnectData::default());
+        server_thread.join().unwrap();
+
+        let (mut request, _) = NetPacket::receive(&master).unwrap();
+        assert_eq!(
+            request.read_u16(),
+            Ok(NetMasterPacketType::NatHolePunch as u16)
+        );
+        assert_eq!(request.read_string(), Ok(server_addr.to_string()));
+        assert!(
+            matches!(result, Err(ConnectError::Rejected(reason)) if reason == "Server is full")
+        );
+    }
+
+    #[test]
+    fn test_connect_rebinds_for_ipv6_server() {
+        let server = UdpSocket::bind("[::1]:0").unwrap();
+        let server_addr = server.local_addr().unwrap();
+
+        let server_thread = std::thread::spawn(move || {
+            let (_, src) = NetPacket::receive(&server).unwrap();
+            let mut reject = NetPacket::new();
+            reject.write_u16(NetPacketType::Rejected as u16);
+            reject.write_string("Server is full");
+            reject.send(&server, &src).unwrap();
+        });
+
+        let mut client = NetClient::new("Player1".to_string(), false);
+        let result = client.connect(server_addr, ConnectData::default());
+        server_thread.join().unwrap();
+
+        assert!(matches!(result, Err(ConnectError::Rejected(_))));
+        assert!(client.transport.local_addr().unwrap().is_ipv6());
+    }
+
+    #[test]
+    fn test_connect_times_out() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut client = NetClient::new("Player1".to_string(), false);
+        client.set_connect_timeout(Duration::from_millis(100));
+
+        let result = client.connect(server.local_addr().unwrap(), ConnectData::default());
+        assert!(matches!(result, Err(ConnectError::Timeout)));
+        assert!(!client.is_connected());
+
+        let (mut syn, _) = NetPacket::receive(&server).unwrap();
+        assert_eq!(syn.read_u16(), Ok(NetPacketType::Syn as u16));
+    }
+
+    #[test]
+    fn test_garbled_reply_fails_the_connect() {
+        let mut client = NetClient::new("Player1".to_string(), false);
+        client.connection.state = ConnectionState::Connecting;
+
+        let mut packet = NetPacket::new();
+        packet.write_u16(NetPacketType::Rejected as u16);
+        packet.write_u8(b'X');
+        packet.reset();
+        client.parse_packet(&mut packet);
+
+        assert_eq!(client.connection.state, ConnectionState::Disconnected);
+        assert!(matches!(
+            client.connect_result,
+            Some(Err(ConnectError::InvalidReply(_)))
+        ));
+    }
+
+    #[test]
+    fn test_disconnect_waits_for_ack() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut client = NetClient::new("Player1".to_string(), false);
+        client.connection = NetConnection::new(server.local_addr().unwrap());
+        client.connection.state = ConnectionState::Connected;
+        client.net_client_connected = true;
+        client.state = ClientState::WaitingLaunch;
+
+        let server_thread = std::thread::spawn(move || {
+            let (mut packet, src) = NetPacket::receive(&server).unwrap();
+            assert_eq!(packet.read_u16(), Ok(NetPacketType::Disconnect as u16));
+
+            let mut ack = NetPacket::new();
+            ack.write_u16(NetPacketType::DisconnectAck as u16);
+            ack.send(&server, &src).unwrap();
+        });
+
+        client.disconnect();
+        server_thread.join().unwrap();
+
+        assert!(!client.is_connected());
+        assert_eq!(client.state, ClientState::Disconnected);
+        assert_eq!(client.disconnect_reason(), Some(DisconnectReason::Local));
+    }
+
+    #[test]
+    fn test_server_disconnect_enters_sleep() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        server
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let mut client = NetClient::new("Player1".to_string(), false);
+        client.connection = NetConnection::new(server.local_addr().unwrap());
+        client.connection.state = ConnectionState::Connected;
+        client.net_client_connected = true;
+        client.state = ClientState::WaitingLaunch;
+
+        let client_port = client.transport.local_addr().unwrap().port();
+        let client_addr = SocketAddr::from(([127, 0, 0, 1], client_port));
+        let mut disconnect = NetPacket::new();
+        disconnect.write_u16(NetPacketType::Disconnect as u16);
+
+        // The server asks twice, as if our first ack was lost
+        for _ in 0..2 {
+            disconnect.send(&server, &client_addr).unwrap();
+            std::thread::sleep(Duration::from_millis(20));
+            client.run();
+
+            let (mut ack, _) = NetPacket::receive(&server).unwrap();
+            assert_eq!(ack.read_u16(), Ok(NetPacketType::DisconnectAck as u16));
+            assert_eq!(client.state, ClientState::DisconnectedSleep);
+            assert_eq!(client.disconnect_reason(), Some(DisconnectReason::Remote));
+        }
+    }
+
+    fn syn_reply(protocol: &str) -> NetPacket {
+        let mut packet = NetPacket::new();
+        packet.write_u16(NetPacketType::Syn as u16);
+        packet.write_string("Chocolate Doom 3.0.1");
+        packet.write_string(protocol);
+        packet.reset();
+        packet
+    }
+
+    #[test]
+    fn test_syn_reply_selects_protocol() {
+        let mut client = NetClient::new("Player1".to_string(), false);
+        client.connection.state = ConnectionState::Connecting;
+
+        client.parse_packet(&mut syn_reply("CHOCOLATE_DOOM_0"));
+
+        assert_eq!(client.connection.state, ConnectionState::Connected);
+        assert_eq!(client.connection.protocol, NetProtocol::ChocolateDoom0);
+        assert_eq!(client.state, ClientState::WaitingLaunch);
+    }
+
+    #[test]
+    fn test_syn_reply_with_unknown_protocol_is_refused() {
+        let mut client = NetClient::new("Player1".to_string(), false);
+        client.connection.state = ConnectionState::Connecting;
+
+        client.parse_packet(&mut syn_reply("SOME_FORK_7"));
+
+        assert_eq!(client.connection.state, ConnectionState::Disconnected);
+        assert_eq!(client.state, ClientState::Disconnected);
+        assert!(matches!(
+            client.connect_result,
+            Some(Err(ConnectError::VersionMismatch { ref server_version }))
+                if server_version == "Chocolate Doom 3.0.1"
+        ));
+    }
+
+    #[test]
+    fn test_say_sends_a_character_per_tic() {
+        let mut client = NetClient::new("Player1".to_string(), false);
+        client.settings = Some(GameSettings::default());
+        assert!(client.say("hi"));
+
+        // A character typed by the caller goes first
+        let typed = TicCmd {
+            chatchar: b'X',
+            ..Default::default()
+        };
+        client.send_ticcmd(&typed, 0);
+        for tic in 1..6 {
+            client.send_ticcmd(&TicCmd::default(), tic);
+        }
+
+        let sent: Vec<u8> = client.send_queue[..6]
+            .iter()
+            .map(|sendobj| sendobj.cmd.cmd.chatchar)
+            .collect();
+        assert_eq!(
+            sent,
+            [
+                b'X',
+                hu_stuff::HU_BROADCAST,
+                b'H',
+                b'I',
+                hu_stuff::KEY_ENTER,
+                0
+            ]
+        );
+        assert_ne!(client.send_queue[1].cmd.diff & NET_TICDIFF_CHATCHAR, 0);
+        assert_eq!(client.send_queue[5].cmd.diff & NET_TICDIFF_CHATCHAR, 0);
+    }
+
+    #[test]
+    fn test_chat_from_other_players_is_reassembled() {
+        let mut client = NetClient::new("Player1".to_string(), false);
+        client.net_client_wait_data.player_names[1][..3].copy_from_slice(&['B', 'o', 'b']);
+        client.settings = Some(GameSettings {
+            num_players: 2,
+            ..Default::default()
+        });
+
+        let events = client.subscribe();
+
+        for c in hu_stuff::encode_message(ChatDestination::Player(0), "hello").unwrap() {
+            let mut cmd = NetFullTicCmd::default();
+            cmd.playeringame[..2].copy_from_slice(&[true, true]);
+            cmd.cmds[1].diff = NET_TICDIFF_CHATCHAR;
+            cmd.cmds[1].cmd.chatchar = c;
+            client.recv_window[0].active = true;
+            client.recv_window[0].cmd = cmd;
+            client.advance_window();
+        }
+
+        let messages: Vec<ChatMessage> = events
+            .try_iter()
+            .filter_map(|event| match event {
+                ClientEvent::Chat(message) => Some(message),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            messages,
+            [ChatMessage {
+                sender: 1,
+                sender_name: "Bob".to_string(),
+                destination: ChatDestination::Player(0),
+                text: "HELLO".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_lobby_state_follows_waiting_data() {
+        let mut client = NetClient::new("Player1".to_string(), false);
+        client.state = ClientState::WaitingLaunch;
+        assert_eq!(client.lobby(), None);
+
+        let mut wait_data = NetWaitData {
+            num_players: 2,
+            num_drones: 1,
+            ready_players: 1,
+            max_players: 4,
+            is_controller: 1,
+            consoleplayer: 1,
+            wad_sha1sum: [0xab; 20],
+            is_freedoom: 1,
+            ..Default::default()
+        };
+        wait_data.player_names[0][..5].copy_from_slice(&['A', 'l', 'i', 'c', 'e']);
+        wait_data.player_addrs[0][..3].copy_from_slice(&['1', ':', '2']);
+        wait_data.player_names[1][..3].copy_from_slice(&['B', 'o', 'b']);
+
+        let mut packet = NetPacket::new();
+        packet.write_wait_data(&wait_data);
+        packet.reset();
+        client.parse_waiting_data(&mut packet).unwrap();
+
+        assert_eq!(
+            client.lobby(),
+            Some(&LobbyState {
+                players: vec![
+                    LobbyPlayer {
+                        name: "Alice".to_string(),
+                        address: "1:2".to_string(),
+                    },
+                    LobbyPlayer {
+                        name: "Bob".to_string(),
+                        address: String::new(),
+                    },
+                ],
+                ready_players: 1,
+                num_drones: 1,
+                max_players: 4,
+                is_controller: true,
+                consoleplayer: Some(1),
+                wad_sha1sum: [0xab; 20],
+                deh_sha1sum: [0; 20],
+                is_freedoom: true,
+            })
+        );
+
+        // Bob leaves
+        wait_data.num_players = 1;
+        wait_data.consoleplayer = 0;
+        let mut packet = NetPacket::new();
+        packet.write_wait_data(&wait_data);
+        packet.reset();
+        client.parse_waiting_data(&mut packet).unwrap();
+
+        let lobby = client.lobby().unwrap();
+        assert_eq!(lobby.players.len(), 1);
+        assert_eq!(lobby.consoleplayer, Some(0));
+    }
+
+    #[test]
+    fn test_checksum_mismatch_is_reported_and_blocks_launch() {
+        let mut client = NetClient::new("Player1".to_string(), false);
+        client.state = ClientState::WaitingLaunch;
+        client.net_local_wad_sha1sum = [0x12; 20];
+        client.set_refuse_mismatched_launch(true);
+        let events = client.subscribe();
+
+        let mut wait_data = NetWaitData {
+            num_players: 1,
+            max_players: 4,
+            wad_sha1sum: [0x34; 20],
+            ..Default::default()
+        };
+        for _ in 0..2 {
+            let mut packet = NetPacket::new();
+            packet.write_wait_data(&wait_data);
+            packet.reset();
+            client.parse_waiting_data(&mut packet).unwrap();
+        }
+
+        let mismatches: Vec<ChecksumMismatch> = events
+            .try_iter()
+            .filter_map(|event| match event {
+                ClientEvent::ChecksumMismatch(mismatch) => Some(mismatch),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(mismatches.len(), 1);
+        assert!(mismatches[0].wad_differs() && !mismatches[0].deh_differs());
+        assert_eq!(
+            mismatches[0].to_string(),
+            format!(
+                "WAD checksum mismatch: ours is {}, the server's is {}",
+                "12".repeat(20),
+                "34".repeat(20)
+            )
+        );
+
+        assert_eq!(client.launch_game(), Err(mismatches[0]));
+        assert!(client.connection.reliable_packets.is_empty());
+
+        wait_data.wad_sha1sum = [0x12; 20];
+        let mut packet = NetPacket::new();
+        packet.write_wait_data(&wait_data);
+        packet.reset();
+        client.parse_waiting_data(&mut packet).unwrap();
+
+        assert_eq!(client.checksum_mismatch(), None);
+        assert_eq!(client.launch_game(), Ok(()));
+    }
+
+    #[test]
+    fn test_controller_policy_gives_up_on_refused_launch() {
+        let mut client = NetClient::new("Player1".to_string(), false);
+        client.state = ClientState::WaitingLaunch;
+        client.net_client_received_wait_data = true;
+        client.net_client_wait_data = NetWaitData {
+            num_players: 2,
+            ready_players: 2,
+            max_players: 4,
+            is_controller: 1,
+            ..Default::default()
+        };
+        client.net_local_wad_sha1sum = [0x12; 20];
+        client.check_checksums();
+        client.set_refuse_mismatched_launch(true);
+        client.set_controller_policy(Some(ControllerPolicy {
+            min_players: 2,
+            ..Default::default()
+        }));
+        let events = client.subscribe();
+
+        for _ in 0..3 {
+            client.run_controller();
+        }
+
+        let refused = events
+            .try_iter()
+            .filter(|event| matches!(event, ClientEvent::AutoLaunchRefused(_)))
+            .count();
+        assert_eq!(refused, 1);
+        assert!(client.connection.reliable_packets.is_empty());
+    }
+
+    #[test]
+    fn test_lifecycle_events() {
+        let mut client = NetClient::new("Player1".to_string(), false);
+        client.connection.state = ConnectionState::Connecting;
+        let events = client.subscribe();
+
+        let wait_data = NetWaitData {
+            num_players: 1,
+            max_players: 4,
+            ..Default::default()
+        };
+        let settings = GameSettings {
+            num_players: 1,
+            ..Default::default()
+        };
+
+        let mut packets = vec![syn_reply("CHOCOLATE_DOOM_0")];
+        for packet_type in [
+            NetPacketType::WaitingData,
+            NetPacketType::Launch,
+            NetPacketType::GameStart,
+            NetPacketType::ConsoleMessage,
+        ] {
+            let mut packet = NetPacket::new();
+            packet.write_u16(packet_type as u16);
+            match packet_type {
+                NetPacketType::WaitingData => packet.write_wait_data(&wait_data),
+                NetPacketType::Launch => packet.write_u8(1),
+                NetPacketType::GameStart => packet.write_settings_and_seed(&settings),
+                _ => packet.write_string("Hello"),
+            }
+            packet.reset();
+            packets.push(packet);
+        }
+        for packet in packets.iter_mut() {
+            client.parse_packet(packet);
+        }
+
+        assert_eq!(
+            events.try_iter().collect::<Vec<_>>(),
+            [
+                ClientEvent::Connected {
+                    server_version: "Chocolate Doom 3.0.1".to_string(),
+                    protocol: NetProtocol::ChocolateDoom0,
+                },
+                ClientEvent::WaitingData(Box::new(wait_data)),
+                ClientEvent::Launched { num_players: 1 },
+                ClientEvent::GameStarted(settings),
+                ClientEvent::ConsoleMessage("Hello".to_string()),
+            ]
+        );
+
+        // Dropping the receiver unsubscribes
+        drop(events);
+        packets.last_mut().unwrap().reset();
+        client.parse_packet(packets.last_mut().unwrap());
+        assert!(client.subscribers.is_empty());
+    }
+
+    #[test]
+    fn test_say_to_addresses_one_player() {
+        let mut client = NetClient::new("Player1".to_string(), false);
+        assert!(!client.say_to(4, "follow me"));
+        assert!(client.chat_queue.is_empty());
+
+        assert!(client.say_to(2, "hi"));
+        assert_eq!(client.chat_queue, [3, b'H', b'I', hu_stuff::KEY_ENTER]);
+
+        assert!(client.say(&"x".repeat(80)));
+        assert!(!client.say(&"x".repeat(80)));
+    }
+
+    #[test]
+    fn test_legacy_handshake_retries_with_server_version() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server_addr = server.local_addr().unwrap();
+
+        let server_thread = std::thread::spawn(move || {
+            let mut versions = Vec::new();
+            loop {
+                let (mut syn, src) = NetPacket::receive(&server).unwrap();
+                assert_eq!(syn.read_u16(), Ok(NetPacketType::Syn as u16));
+                assert_eq!(syn.read_u32(), Ok(NET_OLD_MAGIC_NUMBER));
+                let version = syn.read_string().unwrap();
+                assert!(syn.read_connect_data().is_ok());
+                assert_eq!(syn.read_string(), Ok("Player1".to_string()));
+
+                let mut reply = NetPacket::new();
+                if version == "Chocolate Doom 2.2.1" {
+                    reply.write_u16(NetPacketType::Syn as u16);
+                    reply.write_string("Chocolate Doom 2.2.1");
+                } else {
+                    reply.write_u16(NetPacketType::Rejected as u16);
+                    reply.write_string("Version mismatch: server version is: Chocolate Doom 2.2.1");
+                }
+                reply.send(&server, &src).unwrap();
+
+                versions.push(version);
+                if versions.last().unwrap() == "Chocolate Doom 2.2.1" {
+                    return versions;
+                }
+            }
+        });
+
+        let mut client = NetClient::new("Player1".to_string(), false);
+        client.set_legacy_handshake(true);
+        let info = client.connect(server_addr, ConnectData::default()).unwrap();
+        assert_eq!(info.protocol, NetProtocol::ChocolateDoom0);
+        assert_eq!(info.server_version, "Chocolate Doom 2.2.1");
+
+        let versions = server_thread.join().unwrap();
+        assert_eq!(versions.first().map(String::as_str), Some(LEGACY_VERSION));
+    }
+
+    #[test]
+    fn test_legacy_handshake_falls_back_when_refused() {
+        let mut client = NetClient::new("Player1".to_string(), false);
+        client.set_legacy_handshake(true);
+        client.connection.state = ConnectionState::Connecting;
+
+        let mut reject = NetPacket::new();
+        reject.write_u16(NetPacketType::Rejected as u16);
+        reject.write_string(
+            "You are using an old client version that is not supported by this server. \
+            This server is running Chocolate Doom 3.0.1.",
+        );
+        reject.reset();
+        client.parse_packet(&mut reject);
+
+        assert_eq!(client.connection.state, ConnectionState::Connecting);
+        assert_eq!(client.legacy_version, None);
+    }
+
+    #[test]
+    fn test_legacy_handshake_ignores_other_rejects() {
+        let mut client = NetClient::new("Player1".to_string(), false);
+        client.set_legacy_handshake(true);
+        client.connection.state = ConnectionState::Connecting;
+
+        // Mentions versions, but is not one of the rejects servers send
+        let mut reject = NetPacket::new();
+        reject.write_u16(NetPacketType::Rejected as u16);
+        reject.write_string("Your WAD version is not supported by this server");
+        reject.reset();
+        client.parse_packet(&mut reject);
+
+        assert_eq!(client.connection.state, ConnectionState::Disconnected);
+        assert_eq!(client.legacy_version.as_deref(), Some(LEGACY_VERSION));
+        assert!(matches!(
+            client.connect_result,
+            Some(Err(ConnectError::Rejected(_)))
+        ));
+    }
+
+    #[test]
+    fn test_game_start_has_the_reference_layout() {
+        let mut client = NetClient::new("Player1".to_string(), false);
+        let mut settings = GameSettings {
+            ticdup: 1,
+            extratics: 2,
+            deathmatch: 1,
+            episode: 1,
+            map: 7,
+            skill: -1,
+            timelimit: 0x01020304,
+            loadgame: -1,
+            num_players: 2,
+            consoleplayer: 1,
+            prng_seed: [0x5a; PRNG_SEED_SIZE],
+            ..Default::default()
+        };
+        settings.player_classes[1] = 3;
+        client.start_game(&settings);
+
+        // As NET_WriteSettings writes it, with nothing after the classes
+        let mut expected = (NetPacketType::GameStart as u16 | NET_RELIABLE_PACKET)
+            .to_be_bytes()
+            .to_vec();
+        expected.push(0); // reliable sequence number
+        expected.extend([1, 2, 1, 0, 0, 0, 1, 7, 0xff, 0, 0, 0]);
+        expected.extend([1, 2, 3, 4]);
+        expected.extend([0xff, 0, 2, 1]);
+        expected.extend([0, 3]);
+
+        let packet = &client.connection.reliable_packets.back().unwrap().packet;
+        assert_eq!(packet.data, expected);
+    }
+
+    #[test]
+    fn test_truncated_game_start_is_dropped() {
+        let mut client = NetClient::new("Player1".to_string(), false);
+        client.connection.state = ConnectionState::Connected;
+        client.state = ClientState::WaitingStart;
+
+        let mut packet = NetPacket::new();
+        packet.write_u16(NetPacketType::GameStart as u16);
+        packet.write_u8(1);
+        packet.write_u8(1);
+        packet.reset();
+
+        client.parse_packet(&mut packet);
+
+        assert_eq!(client.state, ClientState::WaitingStart);
+        assert!(client.settings.is_none());
+    }
+
+    proptest! {
+        #[test]
+        fn prop_server_packets_never_panic(
+            packet_type in prop::sample::select(vec![
+                NetPacketType::Syn,
+                NetPacketType::Rejected,
+                NetPacketType::WaitingData,
+                NetPacketType::Launch,
+                NetPacketType::GameStart,
+                NetPacketType::GameData,
+                NetPacketType::GameDataResend,
+                NetPacketType::ConsoleMessage,
+            ]),
+            in_game in any::<bool>(),
+            body in prop::collection::vec(any::<u8>(), 0..256),
+        ) {
+            let mut client = NetClient::new("Player1".to_string(), false);
+            client.connection.state = ConnectionState::Connected;
+            if in_game {
+                client.state = ClientState::InGame;
+                client.settings = Some(GameSettings {
+                    num_players: 1,
+                    ..Default::default()
+                });
+            } else {
+                client.state = ClientState::WaitingStart;
+            }
+
+            let mut packet = NetPacket::new();
+            packet.write_u16(packet_type as u16);
+            packet.data.extend_from_slice(&body);
+
+            client.parse_packet(&mut packet);
+        }
     }
 }
@@ -0,0 +1,504 @@
+use std::io;
+use std::time::{Duration, Instant};
+
+use crate::net_io::Transport;
+use crate::net_packet::NetPacket;
+use crate::net_structs::*;
+
+// Connections time out after 30 seconds without receiving anything
+const CONNECTION_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Maximum time between sending packets
+const KEEPALIVE_PERIOD: Duration = Duration::from_secs(1);
+
+// Time after which an unacknowledged reliable packet is sent again
+const RELIABLE_RESEND_PERIOD: Duration = Duration::from_secs(1);
+
+// Time between disconnect packets while waiting for an acknowledgement
+const DISCONNECT_RESEND_PERIOD: Duration = Duration::from_secs(1);
+
+// Number of disconnect packets sent before giving up on an acknowledgement
+const MAX_RETRIES: u32 = 5;
+
+// Time spent in the sleep state answering stray disconnect packets
+const DISCONNECTED_SLEEP_PERIOD: Duration = Duration::from_secs(5);
+
+impl NetConnection {
+    /// Sends a packet to the other end of the connection.
+    /// All packets should be sent through this interface, as it maintains
+    /// the keepalive send time.
+    pub fn send_packet(
+        &mut self,
+        transport: &dyn Transport,
+        packet: &NetPacket,
+    ) -> io::Result<usize> {
+        self.keepalive_send_time = Instant::now();
+        packet.send(transport, &self.addr)
+    }
+
+    /// Sends a packet whose loss the resend and timeout logic copes with,
+    /// so failing to send it is only logged.
+    pub(crate) fn send_or_log(
+        &mut self,
+        transport: &dyn Transport,
+        packet: &NetPacket,
+        what: &str,
+    ) {
+        if let Err(e) = self.send_packet(transport, packet) {
+            println!("Failed to send {} to {}: {}", what, self.addr, e);
+        }
+    }
+
+    /// Creates a new reliable packet of the given type and adds it to the
+    /// outgoing queue. The returned packet already has its header written
+    /// and can be extended with the packet body.
+    pub fn new_reliable(&mut self, packet_type: NetPacketType) -> &mut NetPacket {
+        let mut packet = NetPacket::new();
+        packet.write_u16(packet_type as u16 | NET_RELIABLE_PACKET);
+        packet.write_u8(self.reliable_send_seq);
+
+        self.reliable_packets.push_back(ReliablePacket {
+            packet,
+            last_send_time: None,
+            seq: self.reliable_send_seq,
+        });
+
+        self.reliable_send_seq = self.reliable_send_seq.wrapping_add(1);
+
+        &mut self.reliable_packets.back_mut().unwrap().packet
+    }
+
+    /// Processes a packet received from the other end of the connection.
+    /// Strips the reliable bit from `packet_type` if present.
+    ///
+    /// Returns `true` if the packet was consumed by the connection code.
+    pub fn process_packet(
+        &mut self,
+        transport: &dyn Transport,
+        packet: &mut NetPacket,
+        packet_type: &mut u16,
+    ) -> bool {
+        self.keepalive_recv_time = Instant::now();
+
+        if *packet_type & NET_RELIABLE_PACKET != 0 {
+            if self.parse_reliable_packet(transport, packet) {
+                // Out of sequence: eat it.
+                return true;
+            }
+
+            *packet_type &= !NET_RELIABLE_PACKET;
+        }
+
+        match NetPacketType::try_from(*packet_type) {
+            Ok(NetPacketType::Disconnect) => self.parse_disconnect(transport),
+            Ok(NetPacketType::DisconnectAck) => self.parse_disconnect_ack(),
+            Ok(NetPacketType::KeepAlive) => {
+                // No special action needed.
+            }
+            Ok(NetPacketType::ReliableAck) => self.parse_reliable_ack(packet),
+            _ => return false,
+        }
+
+        true
+    }
+
+    /// Starts disconnecting from the other end. The disconnect packet is
+    /// sent (and resent) by `run` until it is acknowledged.
+    pub fn disconnect(&mut self) {
+        if !matches!(
+            self.state,
+            ConnectionState::Disconnected
+                | ConnectionState::Disconnecting
+                | ConnectionState::DisconnectedSleep
+        ) {
+            self.state = ConnectionState::Disconnecting;
+            self.disconnect_reason = Some(DisconnectReason::Local);
+            self.last_send_time = None;
+            self.num_retries = 0;
+        }
+    }
+
+    /// Runs the connection: detects timeouts, sends keepalives, retransmits
+    /// the first queued reliable packet if it has not been acknowledged in
+    /// time, and drives the disconnect states.
+    pub fn run(&mut self, transport: &dyn Transport) {
+        match self.state {
+            ConnectionState::Connected => self.run_connected(transport),
+            ConnectionState::Disconnecting => self.run_disconnecting(transport),
+            ConnectionState::DisconnectedSleep => self.run_disconnected_sleep(),
+            _ => {}
+        }
+    }
+
+    fn run_connected(&mut self, transport: &dyn Transport) {
+        let now = Instant::now();
+
+        if now.duration_since(self.keepalive_recv_time) > CONNECTION_TIMEOUT {
+            // Haven't received any packets from the other end in a long
+            // time. Assume disconnected.
+            self.state = ConnectionState::Disconnected;
+            self.disconnect_reason = Some(DisconnectReason::Timeout);
+            return;
+        }
+
+        if now.duration_since(self.keepalive_send_time) > KEEPALIVE_PERIOD {
+            let mut packet = NetPacket::new();
+            packet.write_u16(NetPacketType::KeepAlive as u16);
+            self.send_or_log(transport, &packet, "keepalive");
+        }
+
+        if let Some(rp) = self.reliable_packets.front_mut() {
+            let timed_out = rp
+                .last_send_time
+                .is_none_or(|t| now.duration_since(t) > RELIABLE_RESEND_PERIOD);
+
+            if timed_out {
+                rp.last_send_time = Some(now);
+                let packet = rp.packet.clone();
+                self.send_or_log(transport, &packet, "reliable packet");
+            }
+        }
+    }
+
+    fn run_disconnecting(&mut self, transport: &dyn Transport) {
+        let now = Instant::now();
+
+        // Waiting for a reply to our disconnect request
+        let resend = self
+            .last_send_time
+            .is_none_or(|t| now.duration_since(t) > DISCONNECT_RESEND_PERIOD);
+        if !resend {
+            return;
+        }
+
+        if self.num_retries < MAX_RETRIES {
+            let mut packet = NetPacket::new();
+            packet.write_u16(NetPacketType::Disconnect as u16);
+            self.send_or_log(transport, &packet, "disconnect packet");
+            self.last_send_time = Some(now);
+            self.num_retries += 1;
+        } else {
+            // No more retries allowed: force disconnect.
+            self.state = ConnectionState::Disconnected;
+            self.disconnect_reason = Some(DisconnectReason::Local);
+        }
+    }
+
+    fn run_disconnected_sleep(&mut self) {
+        let slept = self
+            .last_send_time
+            .is_none_or(|t| t.elapsed() > DISCONNECTED_SLEEP_PERIOD);
+
+        if slept {
+            self.state = ConnectionState::Disconnected;
+            self.disconnect_reason = Some(DisconnectReason::Remote);
+        }
+    }
+
+    /// The other end wants to disconnect: acknowledge it and go to sleep
+    /// in case the acknowledgement gets lost.
+    fn parse_disconnect(&mut self, transport: &dyn Transport) {
+        let mut reply = NetPacket::new();
+        reply.write_u16(NetPacketType::DisconnectAck as u16);
+        self.send_or_log(transport, &reply, "disconnect ack");
+
+        self.last_send_time = Some(Instant::now());
+        self.state = ConnectionState::DisconnectedSleep;
+        self.disconnect_reason = Some(DisconnectReason::Remote);
+    }
+
+    fn parse_disconnect_ack(&mut self) {
+        if self.state == ConnectionState::Disconnecting {
+            // Our disconnect request was acknowledged: we are done.
+            self.state = ConnectionState::Disconnected;
+            self.disconnect_reason = Some(DisconnectReason::Local);
+            self.last_send_time = None;
+        }
+    }
+
+    /// Reads the header of a reliable packet and acknowledges it.
+    ///
+    /// Returns `true` if the packet should be discarded (incorrect sequence).
+    fn parse_reliable_packet(&mut self, transport: &dyn Transport, packet: &mut NetPacket) -> bool {
+        let Ok(seq) = packet.read_u8() else {
+            return true;
+        };
+
+        // Packets that are not the next one expected are discarded; the
+        // other end resends them until they arrive in order.
+        let discard = seq != self.reliable_recv_seq;
+        if !discard {
+            self.reliable_recv_seq = self.reliable_recv_seq.wrapping_add(1);
+        }
+
+        let mut reply = NetPacket::new();
+        reply.write_u16(NetPacketType::ReliableAck as u16);
+        reply.write_u8(self.reliable_recv_seq);
+        self.send_or_log(transport, &reply, "reliable ack");
+
+        discard
+    }
+
+    fn parse_reliable_ack(&mut self, packet: &mut NetPacket) {
+        let Ok(seq) = packet.read_u8() else {
+            return;
+        };
+
+        // Is this an acknowledgement for the first packet in the queue?
+        if let Some(rp) = self.reliable_packets.front() {
+            if seq == rp.seq.wrapping_add(1) {
+                self.reliable_packets.pop_front();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{SocketAddr, UdpSocket};
+
+    /// A transport whose sends always fail, like a socket whose route to
+    /// the other end has gone.
+    struct UnreachableTransport;
+
+    impl Transport for UnreachableTransport {
+        fn send(&self, _addr: SocketAddr, _packet: &NetPacket) -> io::Result<usize> {
+            Err(io::Error::new(
+                io::ErrorKind::HostUnreachable,
+                "unreachable",
+            ))
+        }
+
+        fn recv(&self) -> io::Result<(NetPacket, SocketAddr)> {
+            Err(io::ErrorKind::WouldBlock.into())
+        }
+
+        fn local_addr(&self) -> io::Result<SocketAddr> {
+            Ok(SocketAddr::from(([127, 0, 0, 1], 0)))
+        }
+
+        fn resolve(&self, address: &str) -> io::Result<SocketAddr> {
+            address
+                .parse()
+                .map_err(|_| io::ErrorKind::InvalidInput.into())
+        }
+    }
+
+    fn connected_pair() -> (NetConnection, UdpSocket, UdpSocket) {
+        let local = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let remote = UdpSocket::bind("127.0.0.1:0").unwrap();
+        remote
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+
+        let mut conn = NetConnection::new(remote.local_addr().unwrap());
+        conn.state = ConnectionState::Connected;
+
+        (conn, local, remote)
+    }
+
+    fn reliable(packet_type: NetPacketType, seq: u8) -> (NetPacket, u16) {
+        let mut packet = NetPacket::new();
+        packet.write_u16(packet_type as u16 | NET_RELIABLE_PACKET);
+        packet.write_u8(seq);
+        packet.reset();
+        let packet_type = packet.read_u16().unwrap();
+        (packet, packet_type)
+    }
+
+    #[test]
+    fn test_reliable_packet_in_sequence_is_acked_and_passed_on() {
+        let (mut conn, local, remote) = connected_pair();
+        let (mut packet, mut packet_type) = reliable(NetPacketType::Launch, 0);
+
+        assert!(!conn.process_packet(&local, &mut packet, &mut packet_type));
+        assert_eq!(packet_type, NetPacketType::Launch as u16);
+        assert_eq!(conn.reliable_recv_seq, 1);
+
+        let (mut ack, _) = NetPacket::receive(&remote).unwrap();
+        assert_eq!(ack.read_u16(), Ok(NetPacketType::ReliableAck as u16));
+        assert_eq!(ack.read_u8(), Ok(1));
+    }
+
+    #[test]
+    fn test_reliable_packet_out_of_sequence_is_discarded() {
+        let (mut conn, local, remote) = connected_pair();
+        let (mut packet, mut packet_type) = reliable(NetPacketType::Launch, 5);
+
+        assert!(conn.process_packet(&local, &mut packet, &mut packet_type));
+        assert_eq!(conn.reliable_recv_seq, 0);
+
+        let (mut ack, _) = NetPacket::receive(&remote).unwrap();
+        assert_eq!(ack.read_u16(), Ok(NetPacketType::ReliableAck as u16));
+        assert_eq!(ack.read_u8(), Ok(0));
+    }
+
+    #[test]
+    fn test_keepalive_sent_when_idle() {
+        let (mut conn, local, remote) = connected_pair();
+        conn.keepalive_send_time = Instant::now() - Duration::from_secs(2);
+
+        conn.run(&local);
+
+        let (mut sent, _) = NetPacket::receive(&remote).unwrap();
+        assert_eq!(sent.read_u16(), Ok(NetPacketType::KeepAlive as u16));
+        assert_eq!(conn.state, ConnectionState::Connected);
+    }
+
+    #[test]
+    fn test_send_failures_leave_the_connection_running() {
+        let mut conn = NetConnection::new(SocketAddr::from(([127, 0, 0, 1], 2342)));
+        conn.state = ConnectionState::Connected;
+        conn.keepalive_send_time = Instant::now() - KEEPALIVE_PERIOD * 2;
+        conn.new_reliable(NetPacketType::Launch);
+
+        conn.run(&UnreachableTransport);
+
+        // The reliable packet stays queued to be resent later
+        assert_eq!(conn.state, ConnectionState::Connected);
+        assert_eq!(conn.reliable_packets.len(), 1);
+        assert!(conn.reliable_packets[0].last_send_time.is_some());
+    }
+
+    #[test]
+    fn test_disconnect_survives_send_failures() {
+        let mut conn = NetConnection::new(SocketAddr::from(([127, 0, 0, 1], 2342)));
+        conn.state = ConnectionState::Connected;
+
+        // Acks for the other end's packets are lost, not fatal
+        let (mut packet, mut packet_type) = reliable(NetPacketType::Launch, 0);
+        assert!(!conn.process_packet(&UnreachableTransport, &mut packet, &mut packet_type));
+
+        conn.disconnect();
+        for _ in 0..=MAX_RETRIES {
+            conn.last_send_time = Some(Instant::now() - DISCONNECT_RESEND_PERIOD * 2);
+            conn.run(&UnreachableTransport);
+        }
+        assert_eq!(conn.state, ConnectionState::Disconnected);
+        assert_eq!(conn.disconnect_reason, Some(DisconnectReason::Local));
+
+        let mut conn = NetConnection::new(SocketAddr::from(([127, 0, 0, 1], 2342)));
+        conn.state = ConnectionState::Connected;
+        let mut packet = NetPacket::new();
+        let mut packet_type = NetPacketType::Disconnect as u16;
+        assert!(conn.process_packet(&UnreachableTransport, &mut packet, &mut packet_type));
+        assert_eq!(conn.state, ConnectionState::DisconnectedSleep);
+    }
+
+    #[test]
+    fn test_connection_times_out_without_traffic() {
+        let (mut conn, local, _remote) = connected_pair();
+        conn.keepalive_recv_time = Instant::now() - Duration::from_secs(31);
+
+        conn.run(&local);
+
+        assert_eq!(conn.state, ConnectionState::Disconnected);
+        assert_eq!(conn.disconnect_reason, Some(DisconnectReason::Timeout));
+    }
+
+    fn packet_of_type(packet_type: NetPacketType) -> (NetPacket, u16) {
+        let mut packet = NetPacket::new();
+        packet.write_u16(packet_type as u16);
+        packet.reset();
+        let packet_type = packet.read_u16().unwrap();
+        (packet, packet_type)
+    }
+
+    #[test]
+    fn test_disconnect_is_resent_until_acked() {
+        let (mut conn, local, remote) = connected_pair();
+        conn.disconnect();
+        assert_eq!(conn.state, ConnectionState::Disconnecting);
+
+        conn.run(&local);
+        conn.last_send_time = Some(Instant::now() - Duration::from_secs(2));
+        conn.run(&local);
+
+        for _ in 0..2 {
+            let (mut sent, _) = NetPacket::receive(&remote).unwrap();
+            assert_eq!(sent.read_u16(), Ok(NetPacketType::Disconnect as u16));
+        }
+        assert_eq!(conn.num_retries, 2);
+
+        let (mut ack, mut packet_type) = packet_of_type(NetPacketType::DisconnectAck);
+        assert!(conn.process_packet(&local, &mut ack, &mut packet_type));
+        assert_eq!(conn.state, ConnectionState::Disconnected);
+        assert_eq!(conn.disconnect_reason, Some(DisconnectReason::Local));
+    }
+
+    #[test]
+    fn test_disconnect_gives_up_after_max_retries() {
+        let (mut conn, local, _remote) = connected_pair();
+        conn.disconnect();
+
+        for _ in 0..=MAX_RETRIES {
+            conn.last_send_time = None;
+            conn.run(&local);
+        }
+
+        assert_eq!(conn.state, ConnectionState::Disconnected);
+        assert_eq!(conn.disconnect_reason, Some(DisconnectReason::Local));
+    }
+
+    #[test]
+    fn test_remote_disconnect_is_acked_and_sleeps() {
+        let (mut conn, local, remote) = connected_pair();
+
+        let (mut packet, mut packet_type) = packet_of_type(NetPacketType::Disconnect);
+        assert!(conn.process_packet(&local, &mut packet, &mut packet_type));
+        assert_eq!(conn.state, ConnectionState::DisconnectedSleep);
+        assert_eq!(conn.disconnect_reason, Some(DisconnectReason::Remote));
+
+        // A repeated disconnect while sleeping is acked again
+        let (mut packet, mut packet_type) = packet_of_type(NetPacketType::Disconnect);
+        assert!(conn.process_packet(&local, &mut packet, &mut packet_type));
+        for _ in 0..2 {
+            let (mut sent, _) = NetPacket::receive(&remote).unwrap();
+            assert_eq!(sent.read_u16(), Ok(NetPacketType::DisconnectAck as u16));
+        }
+
+        conn.run(&local);
+        assert_eq!(conn.state, ConnectionState::DisconnectedSleep);
+
+        conn.last_send_time = Some(Instant::now() - Duration::from_secs(6));
+        conn.run(&local);
+        assert_eq!(conn.state, ConnectionState::Disconnected);
+        assert_eq!(conn.disconnect_reason, Some(DisconnectReason::Remote));
+    }
+
+    #[test]
+    fn test_new_reliable_is_sent_until_acked() {
+        let (mut conn, local, remote) = connected_pair();
+        conn.new_reliable(NetPacketType::GameStart).write_u8(42);
+        conn.new_reliable(NetPacketType::Launch);
+        assert_eq!(conn.reliable_packets.len(), 2);
+
+        conn.run(&local);
+        let (mut sent, _) = NetPacket::receive(&remote).unwrap();
+        assert_eq!(
+            sent.read_u16(),
+            Ok(NetPacketType::GameStart as u16 | NET_RELIABLE_PACKET)
+        );
+        assert_eq!(sent.read_u8(), Ok(0));
+        assert_eq!(sent.read_u8(), Ok(42));
+
+        // An ack for the wrong sequence number leaves the queue alone
+        let mut ack = NetPacket::new();
+        ack.write_u16(NetPacketType::ReliableAck as u16);
+        ack.write_u8(2);
+        ack.reset();
+        let mut packet_type = ack.read_u16().unwrap();
+        assert!(conn.process_packet(&local, &mut ack, &mut packet_type));
+        assert_eq!(conn.reliable_packets.len(), 2);
+
+        let mut ack = NetPacket::new();
+        ack.write_u16(NetPacketType::ReliableAck as u16);
+        ack.write_u8(1);
+        ack.reset();
+        let mut packet_type = ack.read_u16().unwrap();
+        assert!(conn.process_packet(&local, &mut ack, &mut packet_type));
+        assert_eq!(conn.reliable_packets.len(), 1);
+        assert_eq!(conn.reliable_packets[0].seq, 1);
+    }
+}
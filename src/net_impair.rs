@@ -0,0 +1,264 @@
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use crate::net_io::Transport;
+use crate::net_packet::NetPacket;
+
+// Extra delay for packets picked for reordering, so that the packets sent
+// just after them overtake them
+const REORDER_DELAY: Duration = Duration::from_millis(20);
+
+/// Network conditions to simulate. The default is a perfect network.
+#[derive(Debug, Clone, Default)]
+pub struct Impairment {
+    /// Fraction of packets dropped, from 0 to 1
+    pub loss: f64,
+    /// Delay added to every packet
+    pub latency: Duration,
+    /// Upper bound of a random delay added on top of `latency`
+    pub jitter: Duration,
+    /// Fraction of packets held back so that later packets overtake them
+    pub reorder: f64,
+    /// Fraction of packets delivered twice
+    pub duplicate: f64,
+    /// Link capacity in bytes per second; packets queue up behind each
+    /// other once it is exceeded
+    pub bandwidth: Option<u32>,
+    /// Seed for the random decisions, so that a run can be reproduced
+    pub seed: u64,
+}
+
+struct DelayedPacket {
+    deliver_at: Instant,
+    seq: u64,
+    addr: SocketAddr,
+    packet: NetPacket,
+}
+
+struct ImpairState {
+    rng: StdRng,
+    queue: Vec<DelayedPacket>,
+    next_seq: u64,
+    link_free_at: Instant,
+}
+
+/// Wraps a transport and impairs the packets sent through it. Delayed
+/// packets are handed to the inner transport whenever this one is used to
+/// send or receive, so it has to be polled like any other transport.
+pub struct ImpairedTransport<T> {
+    inner: T,
+    impairment: Impairment,
+    state: Mutex<ImpairState>,
+}
+
+impl<T: Transport> ImpairedTransport<T> {
+    pub fn new(inner: T, impairment: Impairment) -> Self {
+        let state = ImpairState {
+            rng: StdRng::seed_from_u64(impairment.seed),
+            queue: Vec::new(),
+            next_seq: 0,
+            link_free_at: Instant::now(),
+        };
+
+        ImpairedTransport {
+            inner,
+            impairment,
+            state: Mutex::new(state),
+        }
+    }
+
+    /// Hands every packet that is due to the inner transport.
+    fn flush(&self, state: &mut ImpairState) -> io::Result<()> {
+        let now = Instant::now();
+        state.queue.sort_by_key(|p| (p.deliver_at, p.seq));
+
+        let due = state.queue.partition_point(|p| p.deliver_at <= now);
+        for delayed in state.queue.drain(..due) {
+            self.inner.send(delayed.addr, &delayed.packet)?;
+        }
+
+        Ok(())
+    }
+
+    fn schedule(&self, state: &mut ImpairState, addr: SocketAddr, packet: &NetPacket) {
+        let now = Instant::now();
+        let impairment = &self.impairment;
+
+        let mut sent_at = now;
+        if let Some(bandwidth) = impairment.bandwidth {
+            let transmit_time =
+                Duration::from_secs_f64(packet.data.len() as f64 / bandwidth.max(1) as f64);
+            state.link_free_at = state.link_free_at.max(now) + transmit_time;
+            sent_at = state.link_free_at;
+        }
+
+        let mut delay = impairment.latency;
+        if !impairment.jitter.is_zero() {
+            delay += state.rng.gen_range(Duration::ZERO..=impairment.jitter);
+        }
+        if state.rng.gen::<f64>() < impairment.reorder {
+            delay += REORDER_DELAY;
+        }
+
+        state.queue.push(DelayedPacket {
+            deliver_at: sent_at + delay,
+            seq: state.next_seq,
+            addr,
+            packet: packet.clone(),
+        });
+        state.next_seq += 1;
+    }
+}
+
+impl<T: Transport> Transport for ImpairedTransport<T> {
+    fn send(&self, addr: SocketAddr, packet: &NetPacket) -> io::Result<usize> {
+        let mut state = self.state.lock().unwrap();
+
+        if state.rng.gen::<f64>() >= self.impairment.loss {
+            self.schedule(&mut state, addr, packet);
+            if state.rng.gen::<f64>() < self.impairment.duplicate {
+                self.schedule(&mut state, addr, packet);
+            }
+        }
+
+        self.flush(&mut state)?;
+
+        Ok(packet.data.len())
+    }
+
+    fn recv(&self) -> io::Result<(NetPacket, SocketAddr)> {
+        self.flush(&mut self.state.lock().unwrap())?;
+        self.inner.recv()
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    fn addr_to_string(&self, addr: SocketAddr) -> String {
+        self.inner.addr_to_string(addr)
+    }
+
+    fn resolve(&self, address: &str) -> io::Result<SocketAddr> {
+        self.inner.resolve(address)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net_loop::{LoopbackNetwork, LoopbackTransport};
+
+    fn impaired_pair(
+        impairment: Impairment,
+    ) -> (ImpairedTransport<LoopbackTransport>, LoopbackTransport) {
+        let network = LoopbackNetwork::new();
+        let sender = ImpairedTransport::new(network.bind_any(), impairment);
+        (sender, network.bind_any())
+    }
+
+    fn send_numbered(sender: &impl Transport, receiver: &LoopbackTransport, count: u8) {
+        let addr = receiver.local_addr().unwrap();
+        for i in 0..count {
+            let mut packet = NetPacket::new();
+            packet.write_u8(i);
+            packet.send(sender, &addr).unwrap();
+        }
+    }
+
+    /// Polls the sender until `wait` has passed, collecting what arrives.
+    fn drain(sender: &impl Transport, receiver: &LoopbackTransport, wait: Duration) -> Vec<u8> {
+        let deadline = Instant::now() + wait;
+        let mut received = Vec::new();
+
+        loop {
+            let _ = sender.recv();
+            while let Ok((mut packet, _)) = NetPacket::receive(receiver) {
+                received.push(packet.read_u8().unwrap());
+            }
+            if Instant::now() >= deadline {
+                return received;
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn test_perfect_network_delivers_everything_in_order() {
+        let (sender, receiver) = impaired_pair(Impairment::default());
+        send_numbered(&sender, &receiver, 10);
+
+        let received = drain(&sender, &receiver, Duration::ZERO);
+        assert_eq!(received, (0..10).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_latency_holds_packets_back() {
+        let (sender, receiver) = impaired_pair(Impairment {
+            latency: Duration::from_millis(50),
+            ..Default::default()
+        });
+        send_numbered(&sender, &receiver, 1);
+
+        assert!(drain(&sender, &receiver, Duration::from_millis(10)).is_empty());
+        assert_eq!(drain(&sender, &receiver, Duration::from_millis(100)), [0]);
+    }
+
+    #[test]
+    fn test_loss_and_duplication() {
+        let (sender, receiver) = impaired_pair(Impairment {
+            loss: 1.0,
+            ..Default::default()
+        });
+        send_numbered(&sender, &receiver, 10);
+        assert!(drain(&sender, &receiver, Duration::ZERO).is_empty());
+
+        let (sender, receiver) = impaired_pair(Impairment {
+            duplicate: 1.0,
+            ..Default::default()
+        });
+        send_numbered(&sender, &receiver, 2);
+        assert_eq!(drain(&sender, &receiver, Duration::ZERO), [0, 0, 1, 1]);
+    }
+
+    #[test]
+    fn test_same_seed_gives_same_losses() {
+        let impairment = Impairment {
+            loss: 0.5,
+            seed: 42,
+            ..Default::default()
+        };
+
+        let runs: Vec<Vec<u8>> = (0..2)
+            .map(|_| {
+                let (sender, receiver) = impaired_pair(impairment.clone());
+                send_numbered(&sender, &receiver, 50);
+                drain(&sender, &receiver, Duration::ZERO)
+            })
+            .collect();
+
+        assert_eq!(runs[0], runs[1]);
+        assert!(!runs[0].is_empty() && runs[0].len() < 50);
+    }
+
+    #[test]
+    fn test_bandwidth_cap_spaces_packets_out() {
+        let (sender, receiver) = impaired_pair(Impairment {
+            bandwidth: Some(1000),
+            ..Default::default()
+        });
+
+        // 1 byte per packet at 1000 bytes/s: 100 packets take 100ms.
+        send_numbered(&sender, &receiver, 100);
+        let early = drain(&sender, &receiver, Duration::from_millis(20));
+        assert!(early.len() < 100);
+
+        let late = drain(&sender, &receiver, Duration::from_millis(200));
+        assert_eq!(early.len() + late.len(), 100);
+    }
+}
@@ -0,0 +1,180 @@
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+
+use crate::net_packet::NetPacket;
+use crate::net_structs::DEFAULT_PORT;
+
+/// A way of exchanging packets with other nodes, in the spirit of
+/// Chocolate Doom's `net_module_t`. Creating a transport (binding a socket,
+/// attaching to a loopback network) takes the place of the module's init
+/// functions.
+pub trait Transport: Send {
+    /// Sends a packet to `addr`.
+    fn send(&self, addr: SocketAddr, packet: &NetPacket) -> io::Result<usize>;
+
+    /// Receives the next packet and the address it came from. Fails with
+    /// `WouldBlock` when nothing is waiting on a non-blocking transport.
+    fn recv(&self) -> io::Result<(NetPacket, SocketAddr)>;
+
+    /// Returns the address other nodes reach this transport on.
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+
+    /// Formats an address for display.
+    fn addr_to_string(&self, addr: SocketAddr) -> String {
+        addr.to_string()
+    }
+
+    /// Resolves an address string to an address on this transport.
+    fn resolve(&self, address: &str) -> io::Result<SocketAddr>;
+}
+
+impl Transport for UdpSocket {
+    fn send(&self, addr: SocketAddr, packet: &NetPacket) -> io::Result<usize> {
+        self.send_to(&packet.data, addr)
+    }
+
+    fn recv(&self) -> io::Result<(NetPacket, SocketAddr)> {
+        let mut buf = [0u8; 1024]; // Adjust buffer size as needed
+        let (size, src) = self.recv_from(&mut buf)?;
+        let mut packet = NetPacket::new();
+        packet.data.extend_from_slice(&buf[..size]);
+        Ok((packet, src))
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        UdpSocket::local_addr(self)
+    }
+
+    fn resolve(&self, address: &str) -> io::Result<SocketAddr> {
+        resolve_address(address)
+    }
+}
+
+/// Resolves a server address given as `host`, `host:port`, `[v6]:port` or
+/// a bare IPv6 address. The port defaults to 2342 when none is given.
+pub fn resolve_address(address: &str) -> io::Result<SocketAddr> {
+    let (host, port) = split_host_port(address)?;
+
+    let mut addrs = (host, port)
+        .to_socket_addrs()
+        .map_err(|e| io::Error::new(e.kind(), format!("Failed to resolve '{}': {}", host, e)))?;
+
+    addrs.next().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("No addresses found for '{}'", host),
+        )
+    })
+}
+
+/// Splits an address into its host and port, applying the default port.
+fn split_host_port(address: &str) -> io::Result<(&str, u16)> {
+    let invalid = |reason: &str| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid address '{}': {}", address, reason),
+        )
+    };
+
+    let (host, port) = if let Some(rest) = address.strip_prefix('[') {
+        let (host, rest) = rest.split_once(']').ok_or_else(|| invalid("missing ']'"))?;
+        if rest.is_empty() {
+            (host, None)
+        } else {
+            let port = rest
+                .strip_prefix(':')
+                .ok_or_else(|| invalid("expected ':' after ']'"))?;
+            (host, Some(port))
+        }
+    } else if address.matches(':').count() > 1 {
+        // A bare IPv6 address: a port can only be given with brackets
+        (address, None)
+    } else {
+        match address.split_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (address, None),
+        }
+    };
+
+    if host.is_empty() {
+        return Err(invalid("missing host name"));
+    }
+
+    let port = match port {
+        Some(port) => port.parse().map_err(|_| invalid("bad port number"))?,
+        None => DEFAULT_PORT,
+    };
+
+    Ok((host, port))
+}
+
+/// Returns the wildcard address of the same family as `remote`, for binding
+/// a socket that can reach it.
+pub fn unspecified_addr(remote: SocketAddr) -> SocketAddr {
+    match remote {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    }
+}
+
+/// Binds a non-blocking UDP socket to use as a transport.
+pub fn bind_udp(addr: SocketAddr) -> io::Result<UdpSocket> {
+    let socket = UdpSocket::bind(addr)?;
+    socket.set_nonblocking(true)?;
+    Ok(socket)
+}
+
+/// Binds a non-blocking UDP socket able to send to `remote`.
+pub fn bind_udp_for(remote: SocketAddr) -> io::Result<UdpSocket> {
+    bind_udp(unspecified_addr(remote))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_applies_default_port() {
+        let addr = resolve_address("192.0.2.1").unwrap();
+        assert_eq!(addr, "192.0.2.1:2342".parse().unwrap());
+
+        let addr = resolve_address("192.0.2.1:2343").unwrap();
+        assert_eq!(addr, "192.0.2.1:2343".parse().unwrap());
+    }
+
+    #[test]
+    fn test_resolve_ipv6() {
+        let addr = resolve_address("[2001:db8::1]:2343").unwrap();
+        assert_eq!(addr, "[2001:db8::1]:2343".parse().unwrap());
+
+        let addr = resolve_address("[2001:db8::1]").unwrap();
+        assert_eq!(addr, "[2001:db8::1]:2342".parse().unwrap());
+
+        let addr = resolve_address("2001:db8::1").unwrap();
+        assert_eq!(addr, "[2001:db8::1]:2342".parse().unwrap());
+    }
+
+    #[test]
+    fn test_resolve_host_name() {
+        let addr = resolve_address("localhost:2343").unwrap();
+        assert!(addr.ip().is_loopback());
+        assert_eq!(addr.port(), 2343);
+    }
+
+    #[test]
+    fn test_resolve_rejects_bad_addresses() {
+        for address in ["", ":2342", "host:port", "host:99999", "[::1", "[::1]2342"] {
+            let err = resolve_address(address).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput, "{}", address);
+        }
+    }
+
+    #[test]
+    fn test_unspecified_addr_matches_family() {
+        let v4 = unspecified_addr("192.0.2.1:2342".parse().unwrap());
+        assert!(v4.is_ipv4() && v4.ip().is_unspecified());
+
+        let v6 = unspecified_addr("[2001:db8::1]:2342".parse().unwrap());
+        assert!(v6.is_ipv6() && v6.ip().is_unspecified());
+    }
+}
@@ -0,0 +1,155 @@
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+
+use crate::net_io::Transport;
+use crate::net_packet::NetPacket;
+
+// Ports handed out to transports bound to port 0
+const FIRST_EPHEMERAL_PORT: u16 = 49152;
+
+#[derive(Default)]
+struct Network {
+    queues: HashMap<SocketAddr, VecDeque<(NetPacket, SocketAddr)>>,
+    next_port: u16,
+}
+
+/// An in-memory network that loopback transports attach to, so clients and
+/// servers can talk to each other without any sockets. Clones share the
+/// same network.
+#[derive(Clone, Default)]
+pub struct LoopbackNetwork {
+    inner: Arc<Mutex<Network>>,
+}
+
+impl LoopbackNetwork {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Attaches a new transport to the network at `addr`. Port 0 picks a
+    /// free port, as with a UDP socket.
+    pub fn bind(&self, addr: SocketAddr) -> io::Result<LoopbackTransport> {
+        let mut network = self.inner.lock().unwrap();
+        let mut addr = addr;
+
+        if addr.port() == 0 {
+            loop {
+                let port =
+                    FIRST_EPHEMERAL_PORT + network.next_port % (u16::MAX - FIRST_EPHEMERAL_PORT);
+                network.next_port = network.next_port.wrapping_add(1);
+                addr.set_port(port);
+                if !network.queues.contains_key(&addr) {
+                    break;
+                }
+            }
+        }
+
+        if network.queues.contains_key(&addr) {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("{} is already bound", addr),
+            ));
+        }
+
+        network.queues.insert(addr, VecDeque::new());
+
+        Ok(LoopbackTransport {
+            network: self.clone(),
+            addr,
+        })
+    }
+
+    /// Attaches a transport at a free port on 127.0.0.1.
+    pub fn bind_any(&self) -> LoopbackTransport {
+        self.bind((Ipv4Addr::LOCALHOST, 0).into())
+            .expect("Failed to bind loopback transport")
+    }
+}
+
+/// A transport attached to a `LoopbackNetwork`. Packets sent to addresses
+/// nobody is bound to are dropped, as they would be over UDP.
+pub struct LoopbackTransport {
+    network: LoopbackNetwork,
+    addr: SocketAddr,
+}
+
+impl Transport for LoopbackTransport {
+    fn send(&self, addr: SocketAddr, packet: &NetPacket) -> io::Result<usize> {
+        let mut network = self.network.inner.lock().unwrap();
+        if let Some(queue) = network.queues.get_mut(&addr) {
+            let mut packet = packet.clone();
+            packet.reset();
+            queue.push_back((packet, self.addr));
+        }
+        Ok(packet.data.len())
+    }
+
+    fn recv(&self) -> io::Result<(NetPacket, SocketAddr)> {
+        let mut network = self.network.inner.lock().unwrap();
+        network
+            .queues
+            .get_mut(&self.addr)
+            .and_then(|queue| queue.pop_front())
+            .ok_or_else(|| io::Error::from(io::ErrorKind::WouldBlock))
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.addr)
+    }
+
+    fn resolve(&self, address: &str) -> io::Result<SocketAddr> {
+        address
+            .parse()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+    }
+}
+
+impl Drop for LoopbackTransport {
+    fn drop(&mut self) {
+        let mut network = self.network.inner.lock().unwrap();
+        network.queues.remove(&self.addr);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_packets_are_delivered_in_order() {
+        let network = LoopbackNetwork::new();
+        let a = network.bind_any();
+        let b = network.bind_any();
+        assert_ne!(a.local_addr().unwrap(), b.local_addr().unwrap());
+
+        for value in [1, 2] {
+            let mut packet = NetPacket::new();
+            packet.write_u8(value);
+            packet.send(&a, &b.local_addr().unwrap()).unwrap();
+        }
+
+        for value in [1, 2] {
+            let (mut packet, from) = NetPacket::receive(&b).unwrap();
+            assert_eq!(from, a.local_addr().unwrap());
+            assert_eq!(packet.read_u8(), Ok(value));
+        }
+
+        let err = NetPacket::receive(&b).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+    }
+
+    #[test]
+    fn test_bound_address_is_exclusive_until_dropped() {
+        let network = LoopbackNetwork::new();
+        let addr: SocketAddr = "127.0.0.1:2342".parse().unwrap();
+
+        let first = network.bind(addr).unwrap();
+        let err = network.bind(addr).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+
+        drop(first);
+        assert!(network.bind(addr).is_ok());
+    }
+}
@@ -0,0 +1,277 @@
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::net_io::{self, Transport};
+use crate::net_packet::NetPacket;
+use crate::net_query::{self, receive_until};
+use crate::net_structs::*;
+
+/// Address of the public Chocolate Doom master server.
+pub const MASTER_SERVER_ADDRESS: &str = "master.chocolate-doom.org:2342";
+
+// Time to wait for the master server to respond before asking again
+const MASTER_TIMEOUT: Duration = Duration::from_secs(2);
+
+// Number of times a request is sent to the master before giving up
+const MASTER_MAX_ATTEMPTS: u32 = 3;
+
+// Responses may be split over several packets; after the first one
+// arrives, keep listening this long for the rest.
+const MASTER_RESPONSE_GRACE: Duration = Duration::from_millis(200);
+
+/// Asks the master server to have `target` send a hole punch packet to us,
+/// opening its NAT gateway to our traffic. The request must be sent from
+/// the transport we will use to talk to `target`, since the master forwards
+/// the address it sees the request coming from.
+pub fn request_hole_punch(
+    transport: &dyn Transport,
+    master_addr: SocketAddr,
+    target: SocketAddr,
+) -> io::Result<()> {
+    let mut packet = NetPacket::new();
+    packet.write_u16(NetMasterPacketType::NatHolePunch as u16);
+    packet.write_string(&target.to_string());
+    packet.send(transport, &master_addr)?;
+    Ok(())
+}
+
+/// Criteria for picking servers out of the master server's list.
+#[derive(Debug, Default, Clone)]
+pub struct ServerFilter {
+    pub gamemode: Option<i32>,
+    pub gamemission: Option<i32>,
+    pub min_free_slots: i32,
+    pub version: Option<String>,
+}
+
+impl ServerFilter {
+    /// Returns whether a server's query data passes this filter.
+    pub fn matches(&self, data: &NetQueryData) -> bool {
+        self.gamemode.is_none_or(|mode| mode == data.gamemode)
+            && self
+                .gamemission
+                .is_none_or(|mission| mission == data.gamemission)
+            && data.max_players - data.num_players >= self.min_free_slots
+            && self
+                .version
+                .as_ref()
+                .is_none_or(|version| *version == data.version)
+    }
+}
+
+/// Client for the master server that keeps the list of public servers.
+pub struct MasterClient {
+    socket: UdpSocket,
+    master_addr: SocketAddr,
+}
+
+impl MasterClient {
+    pub fn new(master_addr: SocketAddr) -> io::Result<Self> {
+        Ok(MasterClient {
+            socket: UdpSocket::bind(net_io::unspecified_addr(master_addr))?,
+            master_addr,
+        })
+    }
+
+    /// Asks the master server for the addresses of all registered servers.
+    pub fn list_servers(&self) -> io::Result<Vec<SocketAddr>> {
+        let strings = self.request(
+            NetMasterPacketType::Query,
+            NetMasterPacketType::QueryResponse,
+        )?;
+
+        // Entries that do not parse as addresses are skipped
+        Ok(strings.iter().filter_map(|s| s.parse().ok()).collect())
+    }
+
+    /// Asks the master server for the metadata it holds about each
+    /// registered server.
+    pub fn get_metadata(&self) -> io::Result<Vec<String>> {
+        self.request(
+            NetMasterPacketType::GetMetadata,
+            NetMasterPacketType::GetMetadataResponse,
+        )
+    }
+
+    /// Lists the registered servers, queries each of them and returns the
+    /// ones that pass `filter`.
+    pub fn find_servers(
+        &self,
+        filter: &ServerFilter,
+    ) -> io::Result<Vec<(SocketAddr, NetQueryData)>> {
+        let addrs = self.list_servers()?;
+
+        // Query all servers at once; unreachable ones are left out.
+        let results: Vec<_> = thread::scope(|scope| {
+            let handles: Vec<_> = addrs
+                .iter()
+                .map(|&addr| scope.spawn(move || (addr, net_query::query(addr))))
+                .collect();
+
+            handles
+                .into_iter()
+                .map(|handle| handle.join().expect("Query thread panicked"))
+                .collect()
+        });
+
+        Ok(results
+            .into_iter()
+            .filter_map(|(addr, result)| result.ok().map(|data| (addr, data)))
+            .filter(|(_, data)| filter.matches(data))
+            .collect())
+    }
+
+    /// Sends a request to the master and collects the strings carried by
+    /// every response of the expected type.
+    fn request(
+        &self,
+        request_type: NetMasterPacketType,
+        response_type: NetMasterPacketType,
+    ) -> io::Result<Vec<String>> {
+        for _ in 0..MASTER_MAX_ATTEMPTS {
+            let mut packet = NetPacket::new();
+            packet.write_u16(request_type as u16);
+            packet.send(&self.socket, &self.master_addr)?;
+
+            let mut deadline = Instant::now() + MASTER_TIMEOUT;
+            let mut strings = None;
+
+            while let Some((mut packet, src)) = receive_until(&self.socket, deadline)? {
+                if src != self.master_addr {
+                    continue;
+                }
+
+                let Ok(packet_type) = packet.read_u16() else {
+                    continue;
+                };
+                if NetMasterPacketType::try_from(packet_type) != Ok(response_type) {
+                    continue;
+                }
+
+                let strings = strings.get_or_insert_with(Vec::new);
+                while let Ok(s) = packet.read_string() {
+                    strings.push(s);
+                }
+
+                deadline = Instant::now() + MASTER_RESPONSE_GRACE;
+            }
+
+            if let Some(strings) = strings {
+                return Ok(strings);
+            }
+        }
+
+        Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "No response from master server",
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fake_game_server(data: NetQueryData) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+
+        thread::spawn(move || loop {
+            let (_, src) = NetPacket::receive(&socket).unwrap();
+            let mut reply = NetPacket::new();
+            reply.write_u16(NetPacketType::QueryResponse as u16);
+            reply.write_query_data(&data);
+            reply.send(&socket, &src).unwrap();
+        });
+
+        addr
+    }
+
+    fn fake_master(servers: Vec<SocketAddr>) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+
+        thread::spawn(move || loop {
+            let (mut packet, src) = NetPacket::receive(&socket).unwrap();
+            let packet_type = NetMasterPacketType::try_from(packet.read_u16().unwrap());
+
+            // Send one entry per packet, as a long list would be split
+            for server in &servers {
+                let mut reply = NetPacket::new();
+                match packet_type {
+                    Ok(NetMasterPacketType::Query) => {
+                        reply.write_u16(NetMasterPacketType::QueryResponse as u16);
+                        reply.write_string(&server.to_string());
+                    }
+                    Ok(NetMasterPacketType::GetMetadata) => {
+                        reply.write_u16(NetMasterPacketType::GetMetadataResponse as u16);
+                        reply.write_string(&format!("{{\"address\": \"{}\"}}", server));
+                    }
+                    _ => continue,
+                }
+                reply.send(&socket, &src).unwrap();
+            }
+        });
+
+        addr
+    }
+
+    fn query_data(gamemode: i32, num_players: i32) -> NetQueryData {
+        NetQueryData {
+            version: "Chocolate Doom 3.0.1".to_string(),
+            num_players,
+            max_players: 4,
+            gamemode,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_list_servers_and_metadata() {
+        let servers: Vec<SocketAddr> = vec![
+            "192.0.2.1:2342".parse().unwrap(),
+            "192.0.2.2:2343".parse().unwrap(),
+        ];
+        let client = MasterClient::new(fake_master(servers.clone())).unwrap();
+
+        assert_eq!(client.list_servers().unwrap(), servers);
+
+        let metadata = client.get_metadata().unwrap();
+        assert_eq!(metadata.len(), 2);
+        assert!(metadata[1].contains("192.0.2.2:2343"));
+    }
+
+    #[test]
+    fn test_find_servers_applies_filter() {
+        let open = fake_game_server(query_data(2, 1));
+        let full = fake_game_server(query_data(2, 4));
+        let other_mode = fake_game_server(query_data(1, 0));
+        let client = MasterClient::new(fake_master(vec![open, full, other_mode])).unwrap();
+
+        let filter = ServerFilter {
+            gamemode: Some(2),
+            min_free_slots: 1,
+            ..Default::default()
+        };
+        let found = client.find_servers(&filter).unwrap();
+
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].0, open);
+        assert_eq!(found[0].1.num_players, 1);
+    }
+
+    #[test]
+    fn test_filter_matches_version() {
+        let data = query_data(2, 0);
+        let mut filter = ServerFilter {
+            version: Some("Chocolate Doom 3.0.1".to_string()),
+            ..Default::default()
+        };
+        assert!(filter.matches(&data));
+
+        filter.version = Some("Chocolate Doom 2.3.0".to_string());
+        assert!(!filter.matches(&data));
+    }
+}
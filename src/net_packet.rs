@@ -1,12 +1,80 @@
 use serde::{Deserialize, Serialize};
 use std::convert::TryInto;
-use std::io::{self, Read, Write};
-use std::net::UdpSocket;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
 
+use crate::net_io::Transport;
+use crate::net_structrw::Wire;
 use crate::net_structs::*;
 
+/// Error returned when a packet cannot be decoded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PacketError {
+    /// Name of the field being read.
+    pub field: &'static str,
+    /// Offset into the packet at which the field starts.
+    pub offset: usize,
+    pub kind: PacketErrorKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PacketErrorKind {
+    /// The packet ended before the field did.
+    Truncated { expected: usize, available: usize },
+    /// A string has no NUL terminator before the end of the packet.
+    Unterminated,
+    /// The field was read but its value is not acceptable.
+    Invalid,
+}
+
+impl fmt::Display for PacketError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            PacketErrorKind::Truncated {
+                expected,
+                available,
+            } => write!(
+                f,
+                "truncated {} at offset {}: expected {} bytes, {} available",
+                self.field, self.offset, expected, available
+            ),
+            PacketErrorKind::Unterminated => {
+                write!(f, "unterminated {} at offset {}", self.field, self.offset)
+            }
+            PacketErrorKind::Invalid => {
+                write!(f, "invalid {} at offset {}", self.field, self.offset)
+            }
+        }
+    }
+}
+
+impl std::error::Error for PacketError {}
+
+impl PacketError {
+    pub(crate) fn invalid(field: &'static str, offset: usize) -> Self {
+        PacketError {
+            field,
+            offset,
+            kind: PacketErrorKind::Invalid,
+        }
+    }
+}
+
+/// Lets the reader of a composite value name the field that failed,
+/// e.g. `self.read_u8().field("ticdup")?`.
+pub trait FieldContext {
+    fn field(self, name: &'static str) -> Self;
+}
+
+impl<T> FieldContext for Result<T, PacketError> {
+    fn field(self, name: &'static str) -> Self {
+        self.map_err(|e| PacketError { field: name, ..e })
+    }
+}
+
 /// Structure that represents a network packet.
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct NetPacket {
     pub data: Vec<u8>,
     pub pos: usize,
@@ -22,55 +90,8 @@ impl NetPacket {
     }
 
     /// Reads a ticcmd diff from the packet.
-    pub fn read_ticcmd_diff(&mut self, lowres_turn: bool) -> Option<NetTicDiff> {
-        let mut diff = NetTicDiff::default();
-        diff.diff = self.read_u8()? as u32;
-
-        if diff.diff & NET_TICDIFF_FORWARD != 0 {
-            diff.cmd.forwardmove = self.read_i8()?;
-        }
-
-        if diff.diff & NET_TICDIFF_SIDE != 0 {
-            diff.cmd.sidemove = self.read_i8()?;
-        }
-
-        if diff.diff & NET_TICDIFF_TURN != 0 {
-            if lowres_turn {
-                diff.cmd.angleturn = (self.read_i8()? as i16) * 256;
-            } else {
-                diff.cmd.angleturn = self.read_i16()?;
-            }
-        }
-
-        if diff.diff & NET_TICDIFF_BUTTONS != 0 {
-            diff.cmd.buttons = self.read_u8()?;
-        }
-
-        if diff.diff & NET_TICDIFF_CONSISTANCY != 0 {
-            diff.cmd.consistancy = self.read_u8()?;
-        }
-
-        if diff.diff & NET_TICDIFF_CHATCHAR != 0 {
-            diff.cmd.chatchar = self.read_u8()?;
-        } else {
-            diff.cmd.chatchar = 0;
-        }
-
-        if diff.diff & NET_TICDIFF_RAVEN != 0 {
-            diff.cmd.lookfly = self.read_u8()?;
-            diff.cmd.arti = self.read_u8()?;
-        } else {
-            diff.cmd.arti = 0;
-        }
-
-        if diff.diff & NET_TICDIFF_STRIFE != 0 {
-            diff.cmd.buttons2 = self.read_u8()?;
-            diff.cmd.inventory = self.read_i16()? as i32;
-        } else {
-            diff.cmd.inventory = 0;
-        }
-
-        Some(diff)
+    pub fn read_ticcmd_diff(&mut self, lowres_turn: bool) -> Result<NetTicDiff, PacketError> {
+        NetTicDiff::read(self, lowres_turn)
     }
 
     /// Writes an unsigned 8-bit integer to the packet.
@@ -109,65 +130,81 @@ impl NetPacket {
         self.data.push(0); // NUL terminator
     }
 
-    /// Reads an unsigned 8-bit integer from the packet.
-    pub fn read_u8(&mut self) -> Option<u8> {
-        if self.pos < self.data.len() {
-            let value = self.data[self.pos];
-            self.pos += 1;
-            Some(value)
-        } else {
-            None
+    /// Takes the next `len` bytes of the packet, or fails with an error
+    /// naming `field` if there are not that many left.
+    fn read_bytes(&mut self, len: usize, field: &'static str) -> Result<&[u8], PacketError> {
+        let available = self.data.len().saturating_sub(self.pos);
+        if available < len {
+            return Err(PacketError {
+                field,
+                offset: self.pos,
+                kind: PacketErrorKind::Truncated {
+                    expected: len,
+                    available,
+                },
+            });
         }
+
+        let bytes = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    /// Reads an unsigned 8-bit integer from the packet.
+    pub fn read_u8(&mut self) -> Result<u8, PacketError> {
+        Ok(self.read_bytes(1, "u8")?[0])
     }
 
     /// Reads a signed 8-bit integer from the packet.
-    pub fn read_i8(&mut self) -> Option<i8> {
-        self.read_u8().map(|v| v as i8)
+    pub fn read_i8(&mut self) -> Result<i8, PacketError> {
+        self.read_u8().map(|v| v as i8).field("i8")
     }
 
     /// Reads an unsigned 16-bit integer in big-endian order from the packet.
-    pub fn read_u16(&mut self) -> Option<u16> {
-        if self.pos + 2 <= self.data.len() {
-            let bytes = &self.data[self.pos..self.pos + 2];
-            self.pos += 2;
-            Some(u16::from_be_bytes(bytes.try_into().unwrap()))
-        } else {
-            None
-        }
+    pub fn read_u16(&mut self) -> Result<u16, PacketError> {
+        let bytes = self.read_bytes(2, "u16")?;
+        Ok(u16::from_be_bytes(bytes.try_into().unwrap()))
     }
 
     /// Reads a signed 16-bit integer in big-endian order from the packet.
-    pub fn read_i16(&mut self) -> Option<i16> {
-        self.read_u16().map(|v| v as i16)
+    pub fn read_i16(&mut self) -> Result<i16, PacketError> {
+        self.read_u16().map(|v| v as i16).field("i16")
     }
 
     /// Reads an unsigned 32-bit integer in big-endian order from the packet.
-    pub fn read_u32(&mut self) -> Option<u32> {
-        if self.pos + 4 <= self.data.len() {
-            let bytes = &self.data[self.pos..self.pos + 4];
-            self.pos += 4;
-            Some(u32::from_be_bytes(bytes.try_into().unwrap()))
-        } else {
-            None
-        }
+    pub fn read_u32(&mut self) -> Result<u32, PacketError> {
+        let bytes = self.read_bytes(4, "u32")?;
+        Ok(u32::from_be_bytes(bytes.try_into().unwrap()))
     }
 
     /// Reads a signed 32-bit integer in big-endian order from the packet.
-    pub fn read_i32(&mut self) -> Option<i32> {
-        self.read_u32().map(|v| v as i32)
+    pub fn read_i32(&mut self) -> Result<i32, PacketError> {
+        self.read_u32().map(|v| v as i32).field("i32")
     }
 
     /// Reads a string from the packet.
-    /// Returns `None` if a terminating NUL byte is not found before the end of the packet.
-    pub fn read_string(&mut self) -> Option<String> {
-        if let Some(terminator) = self.data[self.pos..].iter().position(|&c| c == 0) {
-            let bytes = &self.data[self.pos..self.pos + terminator];
-            let string = String::from_utf8_lossy(bytes).into_owned();
-            self.pos += terminator + 1; // Skip the NUL terminator
-            Some(string)
-        } else {
-            None
-        }
+    /// Fails if a terminating NUL byte is not found before the end of the packet.
+    pub fn read_string(&mut self) -> Result<String, PacketError> {
+        let remaining = self.data.get(self.pos..).unwrap_or_default();
+        let Some(terminator) = remaining.iter().position(|&c| c == 0) else {
+            return Err(PacketError {
+                field: "string",
+                offset: self.pos,
+                kind: PacketErrorKind::Unterminated,
+            });
+        };
+
+        let string = String::from_utf8_lossy(&remaining[..terminator]).into_owned();
+        self.pos += terminator + 1; // Skip the NUL terminator
+        Ok(string)
+    }
+
+    /// Reads a string from the packet, dropping any characters other than
+    /// printable ASCII and newlines.
+    pub fn read_safe_string(&mut self) -> Result<String, PacketError> {
+        let mut string = self.read_string()?;
+        string.retain(|c| c.is_ascii_graphic() || c == ' ' || c == '\n');
+        Ok(string)
     }
 
     /// Resets the reading position to the beginning of the packet.
@@ -177,188 +214,169 @@ impl NetPacket {
 
     /// Writes connect data to the packet.
     pub fn write_connect_data(&mut self, data: &ConnectData) {
-        self.write_u8(data.gamemode as u8);
-        self.write_u8(data.gamemission as u8);
-        self.write_u8(data.lowres_turn as u8);
-        self.write_u8(data.drone as u8);
-        self.write_u8(data.max_players as u8);
-        self.write_u8(data.is_freedoom as u8);
-        self.data.extend_from_slice(&data.wad_sha1sum);
-        self.data.extend_from_slice(&data.deh_sha1sum);
-        self.write_u8(data.player_class as u8);
+        data.write(self, ());
     }
 
-    /// Reads wait data from the packet.
-    pub fn read_wait_data(&mut self) -> Option<NetWaitData> {
-        let mut data = NetWaitData::default();
-        data.num_players = self.read_u8()? as i32;
-        data.num_drones = self.read_u8()? as i32;
-        data.ready_players = self.read_u8()? as i32;
-        data.max_players = self.read_u8()? as i32;
-        data.is_controller = self.read_u8()? as i32;
-        data.consoleplayer = self.read_i8()? as i32;
-        for i in 0..data.num_players as usize {
-            let name = self.read_string()?;
-            if name.len() >= MAXPLAYERNAME {
-                return None;
-            }
-            data.player_names[i] = ['\0'; MAXPLAYERNAME];
-            for (j, c) in name.chars().enumerate().take(MAXPLAYERNAME) {
-                data.player_names[i][j] = c;
-            }
-            let addr = self.read_string()?;
-            if addr.len() >= MAXPLAYERNAME {
-                return None;
-            }
-            data.player_addrs[i] = ['\0'; MAXPLAYERNAME];
-            for (j, c) in addr.chars().enumerate().take(MAXPLAYERNAME) {
-                data.player_addrs[i][j] = c;
+    /// Reads connect data from the packet.
+    pub fn read_connect_data(&mut self) -> Result<ConnectData, PacketError> {
+        ConnectData::read(self, ())
+    }
+
+    /// Reads a protocol name from the packet.
+    /// Returns `NetProtocol::Unknown` if the name is missing or not one we speak.
+    pub fn read_protocol(&mut self) -> NetProtocol {
+        self.read_string()
+            .map_or(NetProtocol::Unknown, |name| NetProtocol::from_name(&name))
+    }
+
+    /// Writes a protocol name to the packet.
+    pub fn write_protocol(&mut self, protocol: NetProtocol) {
+        let name = protocol
+            .name()
+            .expect("Cannot write the unknown protocol to a packet");
+        self.write_string(name);
+    }
+
+    /// Reads a list of protocols from the packet, returning the last one we
+    /// also support (the list is ordered by increasing preference).
+    pub fn read_protocol_list(&mut self) -> NetProtocol {
+        let Ok(num_protocols) = self.read_u8() else {
+            return NetProtocol::Unknown;
+        };
+
+        let mut result = NetProtocol::Unknown;
+        for _ in 0..num_protocols {
+            let Ok(name) = self.read_string() else {
+                return NetProtocol::Unknown;
+            };
+
+            match NetProtocol::from_name(&name) {
+                NetProtocol::Unknown => {}
+                protocol => result = protocol,
             }
         }
-        self.data[self.pos..self.pos + 20].copy_from_slice(&mut data.wad_sha1sum);
-        self.pos += 20;
-        self.data[self.pos..self.pos + 20].copy_from_slice(&mut data.deh_sha1sum);
-        self.pos += 20;
-        data.is_freedoom = self.read_u8()? as i32;
-        Some(data)
+        result
     }
 
-    /// Reads settings from the packet.
-    pub fn read_settings(&mut self) -> Option<GameSettings> {
-        let mut settings = GameSettings::default();
-        settings.ticdup = self.read_u8()? as i32;
-        settings.extratics = self.read_u8()? as i32;
-        settings.deathmatch = self.read_u8()? as i32;
-        settings.nomonsters = self.read_u8()? as i32;
-        settings.fast_monsters = self.read_u8()? as i32;
-        settings.respawn_monsters = self.read_u8()? as i32;
-        settings.episode = self.read_u8()? as i32;
-        settings.map = self.read_u8()? as i32;
-        settings.skill = self.read_i8()? as i32;
-        settings.gameversion = self.read_u8()? as i32;
-        settings.lowres_turn = self.read_u8()? as i32;
-        settings.new_sync = self.read_u8()? as i32;
-        settings.timelimit = self.read_u32()?;
-        settings.loadgame = self.read_i8()? as i32;
-        settings.random = self.read_u8()? as i32;
-        settings.num_players = self.read_u8()? as i32;
-        settings.consoleplayer = self.read_i8()? as i32;
-        for i in 0..settings.num_players as usize {
-            settings.player_classes[i] = self.read_u8()? as i32;
+    /// Writes the list of all protocols we support to the packet.
+    pub fn write_protocol_list(&mut self) {
+        self.write_u8(NetProtocol::SUPPORTED.len() as u8);
+        for protocol in NetProtocol::SUPPORTED {
+            self.write_protocol(protocol);
         }
-        Some(settings)
     }
 
-    /// Writes settings to the packet.
-    pub fn write_settings(&mut self, settings: &GameSettings) {
-        self.write_u8(settings.ticdup as u8);
-        self.write_u8(settings.extratics as u8);
-        self.write_u8(settings.deathmatch as u8);
-        self.write_u8(settings.nomonsters as u8);
-        self.write_u8(settings.fast_monsters as u8);
-        self.write_u8(settings.respawn_monsters as u8);
-        self.write_u8(settings.episode as u8);
-        self.write_u8(settings.map as u8);
-        self.write_i8(settings.skill as i8);
-        self.write_u8(settings.gameversion as u8);
-        self.write_u8(settings.lowres_turn as u8);
-        self.write_u8(settings.new_sync as u8);
-        self.write_u32(settings.timelimit);
-        self.write_i8(settings.loadgame as i8);
-        self.write_u8(settings.random as u8);
-        self.write_u8(settings.num_players as u8);
-        self.write_i8(settings.consoleplayer as i8);
-        for i in 0..settings.num_players as usize {
-            self.write_u8(settings.player_classes[i] as u8);
-        }
+    /// Reads server query data from the packet.
+    pub fn read_query_data(&mut self) -> Result<NetQueryData, PacketError> {
+        NetQueryData::read(self, ())
     }
 
-    /// Reads a full ticcmd from the packet.
-    pub fn read_full_ticcmd(&mut self, lowres_turn: bool) -> Option<NetFullTicCmd> {
-        let mut cmd = NetFullTicCmd::default();
-        cmd.latency = self.read_i16()? as i32;
+    /// Writes server query data to the packet. The `protocol` field is
+    /// ignored; the list of all protocols we support is written instead.
+    pub fn write_query_data(&mut self, query: &NetQueryData) {
+        query.write(self, ());
+    }
 
-        let bitfield = self.read_u8()?;
-        for i in 0..NET_MAXPLAYERS {
-            cmd.playeringame[i] = (bitfield & (1 << i)) != 0;
-        }
+    /// Reads a SHA1 digest from the packet.
+    pub fn read_sha1sum(&mut self, field: &'static str) -> Result<[u8; 20], PacketError> {
+        let bytes = self.read_bytes(20, field)?;
+        Ok(bytes.try_into().unwrap())
+    }
 
-        for i in 0..NET_MAXPLAYERS {
-            if cmd.playeringame[i] {
-                cmd.cmds[i] = self.read_ticcmd_diff(lowres_turn)?;
-            }
-        }
-        Some(cmd)
+    /// Writes a SHA1 digest to the packet.
+    pub fn write_sha1sum(&mut self, digest: &[u8; 20]) {
+        self.data.extend_from_slice(digest);
     }
 
-    /// Writes a ticcmd diff to the packet.
-    pub fn write_ticcmd_diff(&mut self, diff: &NetTicDiff, lowres_turn: bool) {
-        self.write_u8(diff.diff as u8);
+    /// Reads a PRNG seed from the packet.
+    pub fn read_prng_seed(
+        &mut self,
+        field: &'static str,
+    ) -> Result<[u8; PRNG_SEED_SIZE], PacketError> {
+        let bytes = self.read_bytes(PRNG_SEED_SIZE, field)?;
+        Ok(bytes.try_into().unwrap())
+    }
 
-        if diff.diff & NET_TICDIFF_FORWARD != 0 {
-            self.write_i8(diff.cmd.forwardmove);
-        }
+    /// Writes a PRNG seed to the packet.
+    pub fn write_prng_seed(&mut self, seed: &[u8; PRNG_SEED_SIZE]) {
+        self.data.extend_from_slice(seed);
+    }
 
-        if diff.diff & NET_TICDIFF_SIDE != 0 {
-            self.write_i8(diff.cmd.sidemove);
-        }
+    /// Reads wait data from the packet.
+    pub fn read_wait_data(&mut self) -> Result<NetWaitData, PacketError> {
+        NetWaitData::read(self, ())
+    }
 
-        if diff.diff & NET_TICDIFF_TURN != 0 {
-            if lowres_turn {
-                self.write_i8((diff.cmd.angleturn / 256) as i8);
-            } else {
-                self.write_i16(diff.cmd.angleturn);
-            }
-        }
+    /// Writes wait data to the packet.
+    pub fn write_wait_data(&mut self, data: &NetWaitData) {
+        data.write(self, ());
+    }
 
-        if diff.diff & NET_TICDIFF_BUTTONS != 0 {
-            self.write_u8(diff.cmd.buttons);
-        }
+    /// Reads settings from the packet.
+    pub fn read_settings(&mut self) -> Result<GameSettings, PacketError> {
+        GameSettings::read(self, ())
+    }
 
-        if diff.diff & NET_TICDIFF_CONSISTANCY != 0 {
-            self.write_u8(diff.cmd.consistancy);
-        }
+    /// Writes settings to the packet.
+    pub fn write_settings(&mut self, settings: &GameSettings) {
+        settings.write(self, ());
+    }
 
-        if diff.diff & NET_TICDIFF_CHATCHAR != 0 {
-            self.write_u8(diff.cmd.chatchar);
+    /// Reads the settings of a GameStart sent by a server, followed by the
+    /// PRNG seed if there is one. Servers other than ours end the message
+    /// after the settings.
+    pub fn read_settings_and_seed(&mut self) -> Result<GameSettings, PacketError> {
+        let mut settings = self.read_settings()?;
+        if self.data.len() > self.pos {
+            settings.prng_seed = self.read_prng_seed("settings.prng_seed")?;
         }
+        Ok(settings)
+    }
 
-        if diff.diff & NET_TICDIFF_RAVEN != 0 {
-            self.write_u8(diff.cmd.lookfly);
-            self.write_u8(diff.cmd.arti);
-        }
+    /// Writes settings for a GameStart sent to clients, followed by the
+    /// PRNG seed.
+    pub fn write_settings_and_seed(&mut self, settings: &GameSettings) {
+        self.write_settings(settings);
+        self.write_prng_seed(&settings.prng_seed);
+    }
 
-        if diff.diff & NET_TICDIFF_STRIFE != 0 {
-            self.write_u8(diff.cmd.buttons2);
-            self.write_i16(diff.cmd.inventory as i16);
-        }
+    /// Reads a full ticcmd from the packet.
+    pub fn read_full_ticcmd(&mut self, lowres_turn: bool) -> Result<NetFullTicCmd, PacketError> {
+        NetFullTicCmd::read(self, lowres_turn)
     }
 
-    /// Sends the packet over UDP.
-    pub fn send(&self, socket: &UdpSocket, addr: &std::net::SocketAddr) -> io::Result<usize> {
-        socket.send_to(&self.data, addr)
+    /// Writes a full ticcmd to the packet.
+    pub fn write_full_ticcmd(&mut self, cmd: &NetFullTicCmd, lowres_turn: bool) {
+        cmd.write(self, lowres_turn);
     }
 
-    /// Receives a packet over UDP.
-    pub fn receive(socket: &UdpSocket) -> io::Result<(Self, std::net::SocketAddr)> {
-        let mut buf = [0u8; 1024]; // Adjust buffer size as needed
-        let (size, src) = socket.recv_from(&mut buf)?;
-        let mut packet = NetPacket::new();
-        packet.data.extend_from_slice(&buf[..size]);
-        Ok((packet, src))
+    /// Writes a ticcmd diff to the packet.
+    pub fn write_ticcmd_diff(&mut self, diff: &NetTicDiff, lowres_turn: bool) {
+        diff.write(self, lowres_turn);
+    }
+
+    /// Sends the packet to `addr` over a transport.
+    pub fn send(&self, transport: &dyn Transport, addr: &SocketAddr) -> io::Result<usize> {
+        transport.send(*addr, self)
+    }
+
+    /// Receives a packet from a transport.
+    pub fn receive(transport: &dyn Transport) -> io::Result<(Self, SocketAddr)> {
+        transport.recv()
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use proptest::prelude::*;
 
     #[test]
     fn test_write_and_read_u8() {
         let mut packet = NetPacket::new();
         packet.write_u8(255);
         packet.reset();
-        assert_eq!(packet.read_u8(), Some(255));
+        assert_eq!(packet.read_u8(), Ok(255));
     }
 
     #[test]
@@ -366,7 +384,7 @@ mod tests {
         let mut packet = NetPacket::new();
         packet.write_i8(-128);
         packet.reset();
-        assert_eq!(packet.read_i8(), Some(-128));
+        assert_eq!(packet.read_i8(), Ok(-128));
     }
 
     #[test]
@@ -374,7 +392,7 @@ mod tests {
         let mut packet = NetPacket::new();
         packet.write_u16(65535);
         packet.reset();
-        assert_eq!(packet.read_u16(), Some(65535));
+        assert_eq!(packet.read_u16(), Ok(65535));
     }
 
     #[test]
@@ -382,7 +400,7 @@ mod tests {
         let mut packet = NetPacket::new();
         packet.write_i16(-12345);
         packet.reset();
-        assert_eq!(packet.read_i16(), Some(-12345));
+        assert_eq!(packet.read_i16(), Ok(-12345));
     }
 
     #[test]
@@ -390,7 +408,7 @@ mod tests {
         let mut packet = NetPacket::new();
         packet.write_u32(4294967295);
         packet.reset();
-        assert_eq!(packet.read_u32(), Some(4294967295));
+        assert_eq!(packet.read_u32(), Ok(4294967295));
     }
 
     #[test]
@@ -398,7 +416,7 @@ mod tests {
         let mut packet = NetPacket::new();
         packet.write_i32(-123456789);
         packet.reset();
-        assert_eq!(packet.read_i32(), Some(-123456789));
+        assert_eq!(packet.read_i32(), Ok(-123456789));
     }
 
     #[test]
@@ -406,7 +424,52 @@ mod tests {
         let mut packet = NetPacket::new();
         packet.write_string("Hello");
         packet.reset();
-        assert_eq!(packet.read_string(), Some("Hello".to_string()));
+        assert_eq!(packet.read_string(), Ok("Hello".to_string()));
+    }
+
+    #[test]
+    fn test_write_and_read_protocol_list() {
+        let mut packet = NetPacket::new();
+        packet.write_protocol_list();
+        assert_eq!(packet.data, b"\x01CHOCOLATE_DOOM_0\0");
+        packet.reset();
+        assert_eq!(packet.read_protocol_list(), NetProtocol::ChocolateDoom0);
+    }
+
+    #[test]
+    fn test_read_protocol_list_skips_unknown_protocols() {
+        let mut packet = NetPacket::new();
+        packet.write_u8(2);
+        packet.write_string("CHOCOLATE_DOOM_0");
+        packet.write_string("SOME_FORK_7");
+        packet.reset();
+        assert_eq!(packet.read_protocol_list(), NetProtocol::ChocolateDoom0);
+
+        let mut packet = NetPacket::new();
+        packet.write_u8(1);
+        packet.write_string("SOME_FORK_7");
+        packet.reset();
+        assert_eq!(packet.read_protocol_list(), NetProtocol::Unknown);
+    }
+
+    #[test]
+    fn test_read_query_data_without_protocol_list() {
+        let mut packet = NetPacket::new();
+        packet.write_string("Chocolate Doom 2.3.0");
+        packet.write_u8(0);
+        packet.write_u8(1);
+        packet.write_u8(4);
+        packet.write_u8(2);
+        packet.write_u8(1);
+        packet.write_string("Old server");
+        packet.reset();
+
+        let query = packet.read_query_data().unwrap();
+        assert_eq!(query.version, "Chocolate Doom 2.3.0");
+        assert_eq!(query.num_players, 1);
+        assert_eq!(query.max_players, 4);
+        assert_eq!(query.description, "Old server");
+        assert_eq!(query.protocol, NetProtocol::Unknown);
     }
 
     #[test]
@@ -415,6 +478,105 @@ mod tests {
         packet.write_u8(1);
         packet.write_u8(2);
         packet.reset();
-        assert_eq!(packet.read_u8(), Some(1));
+        assert_eq!(packet.read_u8(), Ok(1));
+    }
+
+    #[test]
+    fn test_read_past_end_reports_field_and_offset() {
+        let mut packet = NetPacket::new();
+        packet.write_u8(1);
+        packet.write_u8(2);
+        packet.reset();
+        packet.read_u8().unwrap();
+
+        assert_eq!(
+            packet.read_u32(),
+            Err(PacketError {
+                field: "u32",
+                offset: 1,
+                kind: PacketErrorKind::Truncated {
+                    expected: 4,
+                    available: 1,
+                },
+            })
+        );
+        // A failed read does not consume anything
+        assert_eq!(packet.read_u8(), Ok(2));
+    }
+
+    #[test]
+    fn test_read_unterminated_string() {
+        let mut packet = NetPacket::new();
+        packet.data.extend_from_slice(b"abc");
+
+        let err = packet.read_string().unwrap_err();
+        assert_eq!(err.kind, PacketErrorKind::Unterminated);
+        assert_eq!(err.offset, 0);
+    }
+
+    #[test]
+    fn test_read_settings_names_failing_field() {
+        let mut packet = NetPacket::new();
+        packet.write_settings(&GameSettings::default());
+        packet.data.truncate(13);
+        packet.reset();
+
+        let err = packet.read_settings().unwrap_err();
+        assert_eq!(err.field, "settings.timelimit");
+        assert_eq!(err.offset, 12);
+        assert_eq!(
+            err.to_string(),
+            "truncated settings.timelimit at offset 12: expected 4 bytes, 1 available"
+        );
+    }
+
+    #[test]
+    fn test_read_settings_and_seed() {
+        let settings = GameSettings {
+            num_players: 2,
+            prng_seed: [0x5a; PRNG_SEED_SIZE],
+            ..Default::default()
+        };
+        let mut packet = NetPacket::new();
+        packet.write_settings_and_seed(&settings);
+        assert_eq!(packet.read_settings_and_seed(), Ok(settings));
+
+        // Other servers end the message before the seed.
+        let mut packet = NetPacket::new();
+        packet.write_settings(&settings);
+        let read = packet.read_settings_and_seed().unwrap();
+        assert_eq!(read.prng_seed, [0; PRNG_SEED_SIZE]);
+        assert_eq!(read.num_players, 2);
+
+        // A seed cut short is an error rather than a missing seed.
+        let mut packet = NetPacket::new();
+        packet.write_settings_and_seed(&settings);
+        packet.data.pop();
+
+        let err = packet.read_settings_and_seed().unwrap_err();
+        assert_eq!(err.field, "settings.prng_seed");
+    }
+
+    proptest! {
+        #[test]
+        fn prop_string_round_trip(string in "[^\u{0}]{0,64}") {
+            let mut packet = NetPacket::new();
+            packet.write_string(&string);
+            prop_assert_eq!(packet.read_string(), Ok(string));
+        }
+
+        #[test]
+        fn prop_decoders_never_panic(data in proptest::collection::vec(any::<u8>(), 0..512)) {
+            let packet = NetPacket { data, pos: 0 };
+
+            let _ = packet.clone().read_wait_data();
+            let _ = packet.clone().read_settings();
+            let _ = packet.clone().read_settings_and_seed();
+            let _ = packet.clone().read_full_ticcmd(false);
+            let _ = packet.clone().read_full_ticcmd(true);
+            let _ = packet.clone().read_query_data();
+            let _ = packet.clone().read_protocol_list();
+            let _ = packet.clone().read_string();
+        }
     }
 }
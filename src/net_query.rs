@@ -0,0 +1,231 @@
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+use crate::net_io;
+use crate::net_packet::NetPacket;
+use crate::net_structs::*;
+
+// Time to wait for a response before sending the query again
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+// Number of times a query is sent before giving up
+const QUERY_MAX_ATTEMPTS: u32 = 3;
+
+/// Queries a server for its version, state, player counts, game
+/// mode/mission, description and protocol.
+pub fn query(addr: SocketAddr) -> io::Result<NetQueryData> {
+    let socket = UdpSocket::bind(net_io::unspecified_addr(addr))?;
+
+    for _ in 0..QUERY_MAX_ATTEMPTS {
+        send_query(&socket, addr)?;
+
+        let deadline = Instant::now() + QUERY_TIMEOUT;
+        while let Some((mut packet, src)) = receive_until(&socket, deadline)? {
+            if src != addr {
+                continue;
+            }
+
+            if let Some(result) = parse_query_response(&mut packet) {
+                return result;
+            }
+        }
+    }
+
+    Err(io::Error::new(
+        io::ErrorKind::TimedOut,
+        "No response from server",
+    ))
+}
+
+/// A server that answered a query broadcast on the local network.
+#[derive(Debug, Clone)]
+pub struct LanServer {
+    pub addr: SocketAddr,
+    pub data: NetQueryData,
+    pub rtt: Duration,
+}
+
+impl LanServer {
+    /// Returns whether we can join this server: it speaks our protocol,
+    /// is still waiting for the game to start and has a free slot.
+    pub fn is_compatible(&self) -> bool {
+        self.data.protocol != NetProtocol::Unknown
+            && self.data.server_state == 0
+            && self.data.num_players < self.data.max_players
+    }
+}
+
+/// Broadcasts a query to the local network on the default port and
+/// collects every server that responds within `window`, fastest first.
+pub fn find_lan_servers(window: Duration) -> io::Result<Vec<LanServer>> {
+    let target = SocketAddr::from((Ipv4Addr::BROADCAST, DEFAULT_PORT));
+    query_broadcast(target, window)
+}
+
+fn query_broadcast(target: SocketAddr, window: Duration) -> io::Result<Vec<LanServer>> {
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    socket.set_broadcast(true)?;
+
+    let start = Instant::now();
+    send_query(&socket, target)?;
+
+    let mut servers: Vec<LanServer> = Vec::new();
+    while let Some((mut packet, src)) = receive_until(&socket, start + window)? {
+        if servers.iter().any(|server| server.addr == src) {
+            continue;
+        }
+
+        if let Some(Ok(data)) = parse_query_response(&mut packet) {
+            servers.push(LanServer {
+                addr: src,
+                data,
+                rtt: start.elapsed(),
+            });
+        }
+    }
+
+    Ok(servers)
+}
+
+fn send_query(socket: &UdpSocket, addr: SocketAddr) -> io::Result<()> {
+    let mut packet = NetPacket::new();
+    packet.write_u16(NetPacketType::Query as u16);
+    packet.send(socket, &addr)?;
+    Ok(())
+}
+
+/// Receives the next packet, giving up once `deadline` has passed.
+pub(crate) fn receive_until(
+    socket: &UdpSocket,
+    deadline: Instant,
+) -> io::Result<Option<(NetPacket, SocketAddr)>> {
+    let remaining = deadline.saturating_duration_since(Instant::now());
+    if remaining.is_zero() {
+        return Ok(None);
+    }
+
+    socket.set_read_timeout(Some(remaining))?;
+
+    match NetPacket::receive(socket) {
+        Ok(received) => Ok(Some(received)),
+        Err(e)
+            if matches!(
+                e.kind(),
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+            ) =>
+        {
+            Ok(None)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Parses a packet received in reply to a query.
+///
+/// Returns `None` if the packet is not a query response at all.
+fn parse_query_response(packet: &mut NetPacket) -> Option<io::Result<NetQueryData>> {
+    let packet_type = packet.read_u16().ok()?;
+    if NetPacketType::try_from(packet_type) != Ok(NetPacketType::QueryResponse) {
+        return None;
+    }
+
+    Some(
+        packet
+            .read_query_data()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    fn fake_server(response: NetQueryData) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+
+        thread::spawn(move || {
+            let (mut packet, src) = NetPacket::receive(&socket).unwrap();
+            assert_eq!(packet.read_u16(), Ok(NetPacketType::Query as u16));
+
+            let mut reply = NetPacket::new();
+            reply.write_u16(NetPacketType::QueryResponse as u16);
+            reply.write_query_data(&response);
+            reply.send(&socket, &src).unwrap();
+        });
+
+        addr
+    }
+
+    fn query_data(description: &str, num_players: i32) -> NetQueryData {
+        NetQueryData {
+            version: "Chocolate Doom 3.0.1".to_string(),
+            num_players,
+            max_players: 4,
+            description: description.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_query_broadcast_collects_every_response() {
+        let first = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let second = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let target = first.local_addr().unwrap();
+        let second_addr = second.local_addr().unwrap();
+
+        // Stand in for two servers on the same network: both answer the
+        // one query that reaches the first socket.
+        thread::spawn(move || {
+            let (_, src) = NetPacket::receive(&first).unwrap();
+
+            for (socket, data) in [
+                (&first, query_data("First", 1)),
+                (&second, query_data("Second", 4)),
+            ] {
+                let mut reply = NetPacket::new();
+                reply.write_u16(NetPacketType::QueryResponse as u16);
+                reply.write_query_data(&data);
+                reply.send(socket, &src).unwrap();
+                reply.send(socket, &src).unwrap();
+            }
+        });
+
+        let servers = query_broadcast(target, Duration::from_millis(300)).unwrap();
+
+        assert_eq!(servers.len(), 2);
+        assert_eq!(servers[0].addr, target);
+        assert_eq!(servers[0].data.description, "First");
+        assert!(servers[0].is_compatible());
+        assert_eq!(servers[1].addr, second_addr);
+        assert_eq!(servers[1].data.description, "Second");
+        assert!(!servers[1].is_compatible());
+        assert!(servers[0].rtt <= servers[1].rtt);
+    }
+
+    #[test]
+    fn test_query_decodes_response() {
+        let addr = fake_server(NetQueryData {
+            version: "Chocolate Doom 3.0.1".to_string(),
+            server_state: 0,
+            num_players: 2,
+            max_players: 4,
+            gamemode: 2,
+            gamemission: 1,
+            description: "Bot arena".to_string(),
+            protocol: NetProtocol::Unknown,
+        });
+
+        let data = query(addr).unwrap();
+
+        assert_eq!(data.version, "Chocolate Doom 3.0.1");
+        assert_eq!(data.num_players, 2);
+        assert_eq!(data.max_players, 4);
+        assert_eq!(data.gamemode, 2);
+        assert_eq!(data.gamemission, 1);
+        assert_eq!(data.description, "Bot arena");
+        assert_eq!(data.protocol, NetProtocol::ChocolateDoom0);
+    }
+}
@@ -0,0 +1,1382 @@
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::net_io::{self, Transport};
+use crate::net_packet::{FieldContext, NetPacket, PacketError};
+use crate::net_structs::*;
+
+// Maximum number of clients (players and drones) connected at once
+pub const MAXNETNODES: usize = 16;
+
+// Time between waiting data packets sent to each client before launch
+const WAITING_DATA_PERIOD: Duration = Duration::from_secs(1);
+
+// Time without game data from a player after which we suspect a deadlock
+const DEADLOCK_PERIOD: Duration = Duration::from_secs(1);
+
+// How far a client may run ahead of the slowest acknowledgement
+const MAX_UNACKED_TICS: u32 = 40;
+
+// How far ahead of the receive window a game with nobody else in it may run
+const MAX_SOLO_LEAD: u32 = 10;
+
+// Server run by this process, driven from the game loop through `run`
+static LOCAL_SERVER: Mutex<Option<NetServer>> = Mutex::new(None);
+
+/// A client connected to the server: either a player or a drone.
+struct NetServerClient {
+    connection: NetConnection,
+    name: String,
+    connect_data: ConnectData,
+    /// Player slot, or `None` for drones.
+    player_number: Option<usize>,
+    last_send_time: Option<Instant>,
+    last_gamedata_time: Instant,
+    /// Sequence number of the next tic to be sent to the client
+    send_seq: u32,
+    /// Every tic before this one has been acknowledged by the client
+    acknowledged: u32,
+    send_queue: Vec<NetFullTicCmd>,
+}
+
+impl NetServerClient {
+    fn is_connected(&self) -> bool {
+        self.connection.state == ConnectionState::Connected
+    }
+
+    fn is_drone(&self) -> bool {
+        self.connect_data.drone != 0
+    }
+}
+
+pub struct NetServer {
+    transport: Box<dyn Transport>,
+    state: ServerState,
+    description: String,
+    clients: Vec<NetServerClient>,
+    /// Game mode and mission adopted from the first player to connect
+    game: Option<(i32, i32)>,
+    settings: GameSettings,
+    recv_window_start: u32,
+    recv_window: Vec<[NetClientRecv; NET_MAXPLAYERS]>,
+}
+
+impl NetServer {
+    pub fn new(addr: SocketAddr) -> io::Result<Self> {
+        Ok(Self::with_transport(Box::new(net_io::bind_udp(addr)?)))
+    }
+
+    /// Creates a server that listens for clients on `transport`.
+    pub fn with_transport(transport: Box<dyn Transport>) -> Self {
+        NetServer {
+            transport,
+            state: ServerState::WaitingLaunch,
+            description: String::new(),
+            clients: Vec::new(),
+            game: None,
+            settings: GameSettings::default(),
+            recv_window_start: 0,
+            recv_window: vec![Default::default(); BACKUPTICS],
+        }
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.transport.local_addr()
+    }
+
+    /// Sets the description reported to clients that query the server.
+    pub fn set_description(&mut self, description: String) {
+        self.description = description;
+    }
+
+    pub fn state(&self) -> ServerState {
+        self.state
+    }
+
+    /// Returns the number of connected players, not counting drones.
+    pub fn num_players(&self) -> usize {
+        self.clients
+            .iter()
+            .filter(|c| c.is_connected() && !c.is_drone())
+            .count()
+    }
+
+    /// Returns the number of connected clients, including drones.
+    pub fn num_clients(&self) -> usize {
+        self.clients.iter().filter(|c| c.is_connected()).count()
+    }
+
+    pub fn run(&mut self) {
+        self.receive_packets();
+
+        for idx in 0..self.clients.len() {
+            self.run_client(idx);
+        }
+
+        self.remove_disconnected_clients();
+
+        if self.state == ServerState::InGame {
+            self.advance_window();
+        }
+    }
+
+    fn receive_packets(&mut self) {
+        loop {
+            match NetPacket::receive(self.transport.as_ref()) {
+                Ok((mut packet, addr)) => self.parse_packet(&mut packet, addr),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    eprintln!("Error receiving packet: {}", e);
+                    break;
+                }
+            }
+        }
+    }
+
+    fn parse_packet(&mut self, packet: &mut NetPacket, addr: SocketAddr) {
+        let mut packet_type = match packet.read_u16() {
+            Ok(packet_type) => packet_type,
+            Err(e) => {
+                println!("Server: Dropping malformed packet from {}: {}", addr, e);
+                return;
+            }
+        };
+
+        if packet_type == NetPacketType::Query as u16 {
+            self.send_query_response(addr);
+            return;
+        }
+
+        let Some(idx) = self.clients.iter().position(|c| c.connection.addr == addr) else {
+            // Anything other than a SYN must come from a connected client
+            if packet_type == NetPacketType::Syn as u16 {
+                if let Err(e) = self.parse_syn(packet, None, addr) {
+                    println!("Server: Dropping malformed SYN from {}: {}", addr, e);
+                }
+            }
+            return;
+        };
+
+        if self.clients[idx].connection.process_packet(
+            self.transport.as_ref(),
+            packet,
+            &mut packet_type,
+        ) {
+            // Packet handled by the common connection code
+            return;
+        }
+
+        if !self.clients[idx].is_connected() {
+            // Only the connection code still cares about this client
+            return;
+        }
+
+        let Ok(packet_type) = NetPacketType::try_from(packet_type) else {
+            println!("Server: Unknown packet type: {}", packet_type);
+            return;
+        };
+
+        let result = match packet_type {
+            NetPacketType::Syn => self.parse_syn(packet, Some(idx), addr),
+            NetPacketType::Launch => {
+                self.parse_launch(idx);
+                Ok(())
+            }
+            NetPacketType::GameStart => self.parse_game_start(idx, packet),
+            NetPacketType::GameData => self.parse_game_data(idx, packet),
+            NetPacketType::GameDataAck => self.parse_game_data_ack(idx, packet),
+            NetPacketType::GameDataResend => self.parse_resend_request(idx, packet),
+            NetPacketType::NatHolePunch => {
+                // Only sent to open the client's NAT gateway; nothing to do.
+                Ok(())
+            }
+            _ => {
+                println!("Server: Unexpected {:?} packet from {}", packet_type, addr);
+                Ok(())
+            }
+        };
+
+        if let Err(e) = result {
+            println!(
+                "Server: Dropping malformed {:?} packet from {}: {}",
+                packet_type, addr, e
+            );
+        }
+    }
+
+    fn parse_syn(
+        &mut self,
+        packet: &mut NetPacket,
+        client: Option<usize>,
+        addr: SocketAddr,
+    ) -> Result<(), PacketError> {
+        let magic = packet.read_u32().field("magic number")?;
+        if magic == NET_OLD_MAGIC_NUMBER {
+            let reject = VersionReject::OldClient {
+                server_version: env!("CARGO_PKG_VERSION").to_string(),
+            };
+            self.send_reject(addr, &reject.to_string());
+            return Ok(());
+        }
+        if magic != NET_MAGIC_NUMBER {
+            println!("Server: Ignoring SYN with bad magic number from {}", addr);
+            return Ok(());
+        }
+
+        let client_version = packet.read_string().field("client version")?;
+        let protocol = packet.read_protocol_list();
+        if protocol == NetProtocol::Unknown {
+            self.send_reject(
+                addr,
+                &format!(
+                    "Version mismatch: server version is: {}; client is: {}. \
+                    No common compatible protocol could be negotiated.",
+                    env!("CARGO_PKG_VERSION"),
+                    client_version
+                ),
+            );
+            return Ok(());
+        }
+
+        let data = packet.read_connect_data()?;
+        let player_name = packet.read_string().field("player name")?;
+
+        // Already connected: our reply must have been lost, so send it again.
+        if let Some(idx) = client {
+            self.send_syn_reply(idx);
+            return Ok(());
+        }
+
+        if self.state != ServerState::WaitingLaunch {
+            self.send_reject(addr, "Server is not currently accepting connections");
+            return Ok(());
+        }
+
+        let num_players = self.num_players();
+        if (data.drone == 0 && num_players >= self.max_players())
+            || self.num_clients() >= MAXNETNODES
+        {
+            self.send_reject(addr, "Server is full!");
+            return Ok(());
+        }
+
+        // Adopt the game mode and mission of the first connecting player,
+        // and make sure everybody else is playing the same game.
+        let game = (data.gamemode, data.gamemission);
+        if num_players == 0 && data.drone == 0 {
+            self.game = Some(game);
+        }
+
+        if let Some((gamemode, gamemission)) = self.game {
+            if game != (gamemode, gamemission) {
+                self.send_reject(
+                    addr,
+                    &format!(
+                        "Game mismatch: server is {} ({}), client is {} ({})",
+                        gamemode, gamemission, data.gamemode, data.gamemission
+                    ),
+                );
+                return Ok(());
+            }
+        }
+
+        let mut connection = NetConnection::new(addr);
+        connection.state = ConnectionState::Connected;
+        connection.protocol = protocol;
+
+        println!("Server: '{}' connected from {}", player_name, addr);
+        self.clients.push(NetServerClient {
+            connection,
+            name: player_name,
+            connect_data: data,
+            player_number: None,
+            last_send_time: None,
+            last_gamedata_time: Instant::now(),
+            send_seq: 0,
+            acknowledged: 0,
+            send_queue: vec![NetFullTicCmd::default(); BACKUPTICS],
+        });
+        self.assign_players();
+        self.send_syn_reply(self.clients.len() - 1);
+
+        Ok(())
+    }
+
+    /// Tells a client it is connected, and which protocol we picked.
+    fn send_syn_reply(&mut self, idx: usize) {
+        let client = &mut self.clients[idx];
+
+        let mut packet = NetPacket::new();
+        packet.write_u16(NetPacketType::Syn as u16);
+        packet.write_string(env!("CARGO_PKG_VERSION"));
+        packet.write_protocol(client.connection.protocol);
+
+        client
+            .connection
+            .send_or_log(self.transport.as_ref(), &packet, "SYN reply");
+    }
+
+    fn send_reject(&self, addr: SocketAddr, reason: &str) {
+        println!("Server: Rejecting {}: {}", addr, reason);
+
+        let mut packet = NetPacket::new();
+        packet.write_u16(NetPacketType::Rejected as u16);
+        packet.write_string(reason);
+        if let Err(e) = packet.send(self.transport.as_ref(), &addr) {
+            println!("Server: Failed to send reject to {}: {}", addr, e);
+        }
+    }
+
+    fn send_query_response(&self, addr: SocketAddr) {
+        let (gamemode, gamemission) = self.game.unwrap_or_default();
+        let query = NetQueryData {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            server_state: (self.state != ServerState::WaitingLaunch) as i32,
+            num_players: self.num_players() as i32,
+            max_players: self.max_players() as i32,
+            gamemode,
+            gamemission,
+            description: self.description.clone(),
+            protocol: NetProtocol::ChocolateDoom0,
+        };
+
+        let mut packet = NetPacket::new();
+        packet.write_u16(NetPacketType::QueryResponse as u16);
+        packet.write_query_data(&query);
+        if let Err(e) = packet.send(self.transport.as_ref(), &addr) {
+            println!("Server: Failed to send query response to {}: {}", addr, e);
+        }
+    }
+
+    /// The controller is the first connected client that is not a drone.
+    /// Only it may launch and start the game.
+    fn controller(&self) -> Option<usize> {
+        self.clients
+            .iter()
+            .position(|c| c.is_connected() && !c.is_drone())
+    }
+
+    /// The controller decides how many players the game is for.
+    fn max_players(&self) -> usize {
+        self.controller().map_or(NET_MAXPLAYERS, |idx| {
+            (self.clients[idx].connect_data.max_players.max(0) as usize).min(NET_MAXPLAYERS)
+        })
+    }
+
+    /// Hands out player numbers to connected players, in connection order.
+    fn assign_players(&mut self) {
+        let mut next = 0;
+        for client in self.clients.iter_mut().filter(|c| c.is_connected()) {
+            client.player_number = if !client.is_drone() && next < NET_MAXPLAYERS {
+                next += 1;
+                Some(next - 1)
+            } else {
+                None
+            };
+        }
+    }
+
+    fn parse_launch(&mut self, idx: usize) {
+        if self.controller() != Some(idx) {
+            println!("Server: Ignoring launch from '{}'", self.clients[idx].name);
+            return;
+        }
+
+        if self.state != ServerState::WaitingLaunch {
+            return;
+        }
+
+        self.assign_players();
+        let num_players = self.num_players() as u8;
+        for client in self.clients.iter_mut().filter(|c| c.is_connected()) {
+            client
+                .connection
+                .new_reliable(NetPacketType::Launch)
+                .write_u8(num_players);
+        }
+
+        println!("Server: Game launched, waiting for the game to start");
+        self.state = ServerState::WaitingStart;
+    }
+
+    fn parse_game_start(&mut self, idx: usize, packet: &mut NetPacket) -> Result<(), PacketError> {
+        if self.controller() != Some(idx) {
+            println!(
+                "Server: Ignoring game start from '{}'",
+                self.clients[idx].name
+            );
+            return Ok(());
+        }
+
+        let mut settings = packet.read_settings()?;
+        if self.state != ServerState::WaitingStart {
+            return Ok(());
+        }
+
+        if !valid_settings(&settings) {
+            println!("Server: Error: Invalid game settings from the controller");
+            return Ok(());
+        }
+
+        self.assign_players();
+
+        // If anyone is recording a demo, everybody has to use low resolution
+        // turning.
+        let players = self.clients.iter().filter(|c| c.is_connected());
+        settings.lowres_turn = players.clone().any(|c| c.connect_data.lowres_turn != 0) as i32;
+        settings.num_players = self.num_players() as i32;
+        settings.prng_seed = rand::random();
+        settings.player_classes = [0; NET_MAXPLAYERS];
+        for client in players {
+            if let Some(player) = client.player_number {
+                settings.player_classes[player] = client.connect_data.player_class;
+            }
+        }
+
+        let now = Instant::now();
+        for client in self.clients.iter_mut().filter(|c| c.is_connected()) {
+            client.last_gamedata_time = now;
+            client.send_seq = 0;
+            client.acknowledged = 0;
+            client.send_queue = vec![NetFullTicCmd::default(); BACKUPTICS];
+
+            settings.consoleplayer = client.player_number.map_or(-1, |p| p as i32);
+            client
+                .connection
+                .new_reliable(NetPacketType::GameStart)
+                .write_settings_and_seed(&settings);
+        }
+
+        println!(
+            "Server: Starting game with {} players",
+            settings.num_players
+        );
+        self.state = ServerState::InGame;
+        self.settings = settings;
+        self.recv_window_start = 0;
+        self.recv_window = vec![Default::default(); BACKUPTICS];
+
+        Ok(())
+    }
+
+    fn parse_game_data(&mut self, idx: usize, packet: &mut NetPacket) -> Result<(), PacketError> {
+        if self.state != ServerState::InGame {
+            return Ok(());
+        }
+
+        // Drones do not contribute any game data.
+        let Some(player) = self.clients[idx].player_number else {
+            return Ok(());
+        };
+
+        let ackseq = packet.read_u8().field("gamedata ackseq")?;
+        let seq = packet.read_u8().field("gamedata seq")?;
+        let num_tics = packet.read_u8().field("gamedata num_tics")?;
+        let lowres_turn = self.settings.lowres_turn != 0;
+
+        let seq = expand_tic_num(self.recv_window_start, seq as u32);
+        let now = Instant::now();
+
+        for i in 0..num_tics as u32 {
+            let latency = packet.read_i16().field("gamedata latency")?;
+            let diff = packet.read_ticcmd_diff(lowres_turn)?;
+
+            // Tics outside the receive window are dropped
+            let index = seq.wrapping_add(i).wrapping_sub(self.recv_window_start) as usize;
+            if index < BACKUPTICS {
+                let recvobj = &mut self.recv_window[index][player];
+                recvobj.active = true;
+                recvobj.latency = latency as i32;
+                recvobj.diff = diff;
+                self.clients[idx].last_gamedata_time = now;
+            }
+        }
+
+        self.update_acks(idx, ackseq);
+
+        // Received out of sequence? Ask for the tics missing before this
+        // packet, unless we already have.
+        let resend_end =
+            (seq.wrapping_sub(self.recv_window_start) as i32).min(BACKUPTICS as i32 - 1);
+        if resend_end <= 0 {
+            return Ok(());
+        }
+
+        let resend_end = resend_end as usize;
+        let mut resend_start = resend_end;
+        while resend_start > 0 {
+            let recvobj = &self.recv_window[resend_start - 1][player];
+            if recvobj.active || recvobj.resend_time.is_some() {
+                break;
+            }
+            resend_start -= 1;
+        }
+
+        if resend_start < resend_end {
+            self.send_resend_request(
+                idx,
+                self.recv_window_start + resend_start as u32,
+                self.recv_window_start + resend_end as u32 - 1,
+            );
+        }
+
+        Ok(())
+    }
+
+    fn parse_game_data_ack(
+        &mut self,
+        idx: usize,
+        packet: &mut NetPacket,
+    ) -> Result<(), PacketError> {
+        if self.state != ServerState::InGame {
+            return Ok(());
+        }
+
+        let ackseq = packet.read_u8().field("gamedata ack")?;
+        self.update_acks(idx, ackseq);
+
+        Ok(())
+    }
+
+    fn parse_resend_request(
+        &mut self,
+        idx: usize,
+        packet: &mut NetPacket,
+    ) -> Result<(), PacketError> {
+        if self.state != ServerState::InGame {
+            return Ok(());
+        }
+
+        let start = packet.read_i32().field("resend start")? as u32;
+        let num_tics = packet.read_u8().field("resend num_tics")?;
+
+        // Check we have the tics being requested. If not, reduce the
+        // window of tics to only what we have.
+        let send_seq = self.clients[idx].send_seq;
+        let have_tic = |tic: u32| tic < send_seq && send_seq - tic <= BACKUPTICS as u32;
+        let requested = (0..num_tics as u32).map(|i| start.wrapping_add(i));
+        let resend_start = requested.clone().find(|&tic| have_tic(tic));
+        let resend_end = requested.rev().find(|&tic| have_tic(tic));
+
+        if let (Some(resend_start), Some(resend_end)) = (resend_start, resend_end) {
+            self.send_tics(idx, resend_start, resend_end);
+        }
+
+        Ok(())
+    }
+
+    /// Records how far a client has got through the tics we sent it.
+    fn update_acks(&mut self, idx: usize, ackseq: u8) {
+        let client = &mut self.clients[idx];
+        let ackseq = expand_tic_num(client.acknowledged, ackseq as u32).min(client.send_seq);
+
+        if ackseq > client.acknowledged {
+            client.acknowledged = ackseq;
+        }
+    }
+
+    fn send_resend_request(&mut self, idx: usize, start: u32, end: u32) {
+        let client = &mut self.clients[idx];
+
+        let mut packet = NetPacket::new();
+        packet.write_u16(NetPacketType::GameDataResend as u16);
+        packet.write_i32(start as i32);
+        packet.write_u8((end - start + 1) as u8);
+
+        client
+            .connection
+            .send_or_log(self.transport.as_ref(), &packet, "resend request");
+
+        let Some(player) = client.player_number else {
+            return;
+        };
+
+        let now = Instant::now();
+        for tic in start..=end {
+            let index = tic.wrapping_sub(self.recv_window_start) as usize;
+            if index >= BACKUPTICS {
+                break;
+            }
+            self.recv_window[index][player].resend_time = Some(now);
+        }
+    }
+
+    fn send_tics(&mut self, idx: usize, start: u32, end: u32) {
+        let lowres_turn = self.settings.lowres_turn != 0;
+        let client = &mut self.clients[idx];
+
+        let mut packet = NetPacket::new();
+        packet.write_u16(NetPacketType::GameData as u16);
+        packet.write_u8((start & 0xff) as u8);
+        packet.write_u8((end - start + 1) as u8);
+
+        for tic in start..=end {
+            packet.write_full_ticcmd(&client.send_queue[tic as usize % BACKUPTICS], lowres_turn);
+        }
+
+        client
+            .connection
+            .send_or_log(self.transport.as_ref(), &packet, "game data");
+    }
+
+    fn send_waiting_data(&mut self, idx: usize) {
+        let mut wait_data = NetWaitData {
+            num_players: self.num_players() as i32,
+            num_drones: (self.num_clients() - self.num_players()) as i32,
+            // There is no separate ready handshake: every connected player
+            // is ready to play.
+            ready_players: self.num_players() as i32,
+            max_players: self.max_players() as i32,
+            is_controller: (self.controller() == Some(idx)) as i32,
+            consoleplayer: self.clients[idx].player_number.map_or(-1, |p| p as i32),
+            ..Default::default()
+        };
+
+        for client in self.clients.iter().filter(|c| c.is_connected()) {
+            if let Some(player) = client.player_number {
+                wait_data.player_names[player] = player_string(&client.name);
+                wait_data.player_addrs[player] = player_string(&client.connection.addr.to_string());
+            }
+        }
+
+        if let Some(controller) = self.controller() {
+            let data = &self.clients[controller].connect_data;
+            wait_data.wad_sha1sum = data.wad_sha1sum;
+            wait_data.deh_sha1sum = data.deh_sha1sum;
+            wait_data.is_freedoom = data.is_freedoom;
+        }
+
+        let mut packet = NetPacket::new();
+        packet.write_u16(NetPacketType::WaitingData as u16);
+        packet.write_wait_data(&wait_data);
+
+        self.clients[idx]
+            .connection
+            .send_or_log(self.transport.as_ref(), &packet, "waiting data");
+    }
+
+    /// Sends a message to be shown on the console of every client.
+    fn broadcast_message(&mut self, msg: &str) {
+        println!("Server: {}", msg);
+
+        let mut packet = NetPacket::new();
+        packet.write_u16(NetPacketType::ConsoleMessage as u16);
+        packet.write_string(msg);
+
+        for client in self.clients.iter_mut().filter(|c| c.is_connected()) {
+            client
+                .connection
+                .send_or_log(self.transport.as_ref(), &packet, "console message");
+        }
+    }
+
+    fn run_client(&mut self, idx: usize) {
+        let client = &mut self.clients[idx];
+        client.connection.run(self.transport.as_ref());
+
+        if !client.is_connected() {
+            return;
+        }
+
+        match self.state {
+            ServerState::WaitingLaunch => {
+                let due = client
+                    .last_send_time
+                    .is_none_or(|t| t.elapsed() > WAITING_DATA_PERIOD);
+                if due {
+                    client.last_send_time = Some(Instant::now());
+                    self.send_waiting_data(idx);
+                }
+            }
+            ServerState::InGame => {
+                self.pump_send_queue(idx);
+                self.check_deadlock(idx);
+            }
+            ServerState::WaitingStart => {}
+        }
+    }
+
+    fn remove_disconnected_clients(&mut self) {
+        let mut idx = 0;
+        while idx < self.clients.len() {
+            if self.clients[idx].connection.state != ConnectionState::Disconnected {
+                idx += 1;
+                continue;
+            }
+
+            let client = self.clients.remove(idx);
+            if client.connection.disconnect_reason == Some(DisconnectReason::Timeout) {
+                self.broadcast_message(&format!(
+                    "Client '{}' timed out and disconnected",
+                    client.name
+                ));
+            }
+
+            // Any player disconnecting while we are about to start the game
+            // aborts the start.
+            if self.state == ServerState::WaitingStart && !client.is_drone() {
+                self.broadcast_message(&format!(
+                    "Game startup aborted because player '{}' disconnected.",
+                    client.name
+                ));
+                self.game_ended();
+            }
+
+            // Nobody left: go back to waiting for players.
+            if self.num_players() == 0 {
+                self.game_ended();
+            }
+
+            // Player numbers only stay fixed once the game is running
+            if self.state != ServerState::InGame {
+                self.assign_players();
+            }
+        }
+    }
+
+    /// Returns to waiting for players, disconnecting any drones.
+    fn game_ended(&mut self) {
+        self.state = ServerState::WaitingLaunch;
+        self.game = None;
+
+        for client in self.clients.iter_mut() {
+            if client.is_connected() && client.is_drone() {
+                client.connection.disconnect();
+            }
+        }
+    }
+
+    /// Returns the lowest tic acknowledged by every connected client.
+    fn latest_acknowledged(&self) -> u32 {
+        self.clients
+            .iter()
+            .filter(|c| c.is_connected())
+            .map(|c| c.acknowledged)
+            .min()
+            .unwrap_or(u32::MAX)
+    }
+
+    /// Drops tics from the receive window once every client has them.
+    fn advance_window(&mut self) {
+        if self.num_players() == 0 {
+            return;
+        }
+
+        let lowtic = self.latest_acknowledged();
+
+        while self.recv_window_start < lowtic {
+            // Check we have tics from all players for the first tic in the
+            // receive window
+            let complete = self
+                .clients
+                .iter()
+                .filter(|c| c.is_connected())
+                .filter_map(|c| c.player_number)
+                .all(|player| self.recv_window[0][player].active);
+            if !complete {
+                break;
+            }
+
+            self.recv_window.rotate_left(1);
+            self.recv_window[BACKUPTICS - 1] = Default::default();
+            self.recv_window_start += 1;
+        }
+    }
+
+    /// Merges the ticcmds of every player into the next tic for a client,
+    /// once we have them all, and sends it.
+    fn pump_send_queue(&mut self, idx: usize) {
+        let send_seq = self.clients[idx].send_seq;
+
+        // If a client has not acknowledged anything for a while, wait for
+        // it to catch up.
+        if send_seq.saturating_sub(self.latest_acknowledged()) > MAX_UNACKED_TICS {
+            return;
+        }
+
+        let recv_index = send_seq.wrapping_sub(self.recv_window_start) as usize;
+        if recv_index >= BACKUPTICS {
+            return;
+        }
+
+        let mut cmd = NetFullTicCmd {
+            seq: send_seq,
+            ..Default::default()
+        };
+        let mut num_players = 0;
+
+        for (i, other) in self.clients.iter().enumerate() {
+            let Some(player) = other.player_number.filter(|_| other.is_connected()) else {
+                continue;
+            };
+            let recvobj = &self.recv_window[recv_index][player];
+
+            cmd.playeringame[player] = true;
+            cmd.cmds[player] = recvobj.diff;
+
+            if i == idx {
+                // A client does not rely on us for its own ticcmds, but
+                // does want to know how late they are reaching us.
+                cmd.latency = recvobj.latency;
+                continue;
+            }
+
+            if !recvobj.active {
+                // Still waiting for this player's ticcmd
+                return;
+            }
+
+            num_players += 1;
+        }
+
+        // With nobody else in the game there is nothing to wait for, so
+        // stop the client running too far ahead.
+        if num_players == 0 && send_seq > self.recv_window_start + MAX_SOLO_LEAD {
+            return;
+        }
+
+        let extratics = (self.settings.extratics.max(0) as u32).min(BACKUPTICS as u32 - 1);
+        let client = &mut self.clients[idx];
+        client.send_queue[send_seq as usize % BACKUPTICS] = cmd;
+        client.send_seq += 1;
+
+        self.send_tics(idx, send_seq.saturating_sub(extratics), send_seq);
+    }
+
+    /// A player we have heard nothing from in a while may be waiting for us
+    /// to ask again for a tic we lost: ask for the first one we are missing.
+    fn check_deadlock(&mut self, idx: usize) {
+        let client = &self.clients[idx];
+        let Some(player) = client.player_number else {
+            return;
+        };
+
+        if client.last_gamedata_time.elapsed() <= DEADLOCK_PERIOD {
+            return;
+        }
+
+        if let Some(index) = (0..BACKUPTICS).find(|&i| !self.recv_window[i][player].active) {
+            let tic = self.recv_window_start + index as u32;
+            self.send_resend_request(idx, tic, tic);
+        }
+
+        self.clients[idx].last_gamedata_time = Instant::now();
+    }
+}
+
+/// Checks the settings sent by the controller describe a game we can run.
+fn valid_settings(settings: &GameSettings) -> bool {
+    settings.ticdup > 0
+        && settings.extratics >= 0
+        && (0..=2).contains(&settings.deathmatch)
+        && (-1..=4).contains(&settings.skill)
+}
+
+/// Expands an 8-bit tic number to the full tic number closest to
+/// `relative`.
+fn expand_tic_num(relative: u32, b: u32) -> u32 {
+    let l = relative & 0xff;
+    let h = relative & !0xff;
+    let mut result = h | b;
+
+    if l < 0x40 && b > 0xb0 {
+        result = result.wrapping_sub(0x100);
+    }
+    if l > 0xb0 && b < 0x40 {
+        result = result.wrapping_add(0x100);
+    }
+
+    result
+}
+
+/// Copies a string into a player string buffer, truncated so that it fits
+/// along with its terminator.
+fn player_string(s: &str) -> [char; MAXPLAYERNAME] {
+    let mut buf = ['\0'; MAXPLAYERNAME];
+    let mut len = 0;
+    let chars = s.chars().take_while(|c| {
+        len += c.len_utf8();
+        len < MAXPLAYERNAME
+    });
+
+    for (c, slot) in chars.zip(buf.iter_mut()) {
+        *slot = c;
+    }
+    buf
+}
+
+/// Starts a server on `addr` to be run alongside the local client, and
+/// returns the address it is listening on.
+pub fn init(addr: SocketAddr) -> io::Result<SocketAddr> {
+    let server = NetServer::new(addr)?;
+    let local_addr = server.local_addr()?;
+    *LOCAL_SERVER.lock().unwrap() = Some(server);
+    Ok(local_addr)
+}
+
+/// Runs the local server, if one was started.
+pub fn run() {
+    if let Some(server) = LOCAL_SERVER.lock().unwrap().as_mut() {
+        server.run();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net_client::{ConnectError, ControllerPolicy, NetClient};
+    use crate::net_impair::{ImpairedTransport, Impairment};
+    use crate::net_loop::{LoopbackNetwork, LoopbackTransport};
+    use std::net::UdpSocket;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+
+    fn local_server() -> (NetServer, UdpSocket) {
+        let server = NetServer::new("127.0.0.1:0".parse().unwrap()).unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        (server, client)
+    }
+
+    fn syn(protocol: &str, data: &ConnectData) -> NetPacket {
+        let mut packet = NetPacket::new();
+        packet.write_u16(NetPacketType::Syn as u16);
+        packet.write_u32(NET_MAGIC_NUMBER);
+        packet.write_string("test");
+        packet.write_u8(1);
+        packet.write_string(protocol);
+        packet.write_connect_data(data);
+        packet.write_string("Player");
+        packet
+    }
+
+    fn player(max_players: i32) -> ConnectData {
+        ConnectData {
+            max_players,
+            ..Default::default()
+        }
+    }
+
+    /// Sends a packet to the server, runs it and returns its reply.
+    fn exchange(server: &mut NetServer, client: &UdpSocket, packet: &NetPacket) -> NetPacket {
+        packet.send(client, &server.local_addr().unwrap()).unwrap();
+        thread::sleep(Duration::from_millis(10));
+        server.run();
+        NetPacket::receive(client).unwrap().0
+    }
+
+    #[test]
+    fn test_syn_is_accepted_with_common_protocol() {
+        let (mut server, client) = local_server();
+
+        let mut reply = exchange(&mut server, &client, &syn("CHOCOLATE_DOOM_0", &player(4)));
+        assert_eq!(reply.read_u16(), Ok(NetPacketType::Syn as u16));
+        assert_eq!(
+            reply.read_string(),
+            Ok(env!("CARGO_PKG_VERSION").to_string())
+        );
+        assert_eq!(reply.read_protocol(), NetProtocol::ChocolateDoom0);
+
+        assert_eq!(server.num_players(), 1);
+        assert_eq!(server.clients[0].player_number, Some(0));
+    }
+
+    #[test]
+    fn test_syn_without_common_protocol_is_rejected() {
+        let (mut server, client) = local_server();
+
+        let mut reply = exchange(&mut server, &client, &syn("NOT_A_PROTOCOL", &player(4)));
+        assert_eq!(reply.read_u16(), Ok(NetPacketType::Rejected as u16));
+        let reason = reply.read_string().unwrap();
+        assert_eq!(
+            VersionReject::parse(&reason),
+            Some(VersionReject::VersionMismatch {
+                server_version: env!("CARGO_PKG_VERSION").to_string()
+            })
+        );
+        assert_eq!(server.num_clients(), 0);
+    }
+
+    #[test]
+    fn test_syn_rejected_when_full() {
+        let (mut server, first) = local_server();
+        exchange(&mut server, &first, &syn("CHOCOLATE_DOOM_0", &player(1)));
+
+        let second = UdpSocket::bind("127.0.0.1:0").unwrap();
+        second
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let mut reply = exchange(&mut server, &second, &syn("CHOCOLATE_DOOM_0", &player(4)));
+        assert_eq!(reply.read_u16(), Ok(NetPacketType::Rejected as u16));
+        assert_eq!(reply.read_string(), Ok("Server is full!".to_string()));
+        assert_eq!(server.num_clients(), 1);
+    }
+
+    #[test]
+    fn test_old_syn_is_rejected() {
+        let (mut server, client) = local_server();
+        let mut packet = NetPacket::new();
+        packet.write_u16(NetPacketType::Syn as u16);
+        packet.write_u32(NET_OLD_MAGIC_NUMBER);
+        packet.write_string("Chocolate Doom 2.3.0");
+        packet.write_connect_data(&player(4));
+        packet.write_string("Player");
+
+        let mut reply = exchange(&mut server, &client, &packet);
+        assert_eq!(reply.read_u16(), Ok(NetPacketType::Rejected as u16));
+        assert_eq!(
+            VersionReject::parse(&reply.read_string().unwrap()),
+            Some(VersionReject::OldClient {
+                server_version: env!("CARGO_PKG_VERSION").to_string(),
+            })
+        );
+        assert_eq!(server.num_clients(), 0);
+    }
+
+    /// Delivers packets to the server but fails every send, as if no
+    /// client could be reached.
+    struct SendsFail(LoopbackTransport);
+
+    impl Transport for SendsFail {
+        fn send(&self, _addr: SocketAddr, _packet: &NetPacket) -> io::Result<usize> {
+            Err(io::Error::new(
+                io::ErrorKind::HostUnreachable,
+                "unreachable",
+            ))
+        }
+
+        fn recv(&self) -> io::Result<(NetPacket, SocketAddr)> {
+            self.0.recv()
+        }
+
+        fn local_addr(&self) -> io::Result<SocketAddr> {
+            self.0.local_addr()
+        }
+
+        fn resolve(&self, address: &str) -> io::Result<SocketAddr> {
+            self.0.resolve(address)
+        }
+    }
+
+    #[test]
+    fn test_send_failures_do_not_stop_the_server() {
+        let network = LoopbackNetwork::new();
+        let mut server = NetServer::with_transport(Box::new(SendsFail(network.bind_any())));
+        let client = network.bind_any();
+        let addr = server.local_addr().unwrap();
+
+        let mut query = NetPacket::new();
+        query.write_u16(NetPacketType::Query as u16);
+        query.send(&client, &addr).unwrap();
+        syn("CHOCOLATE_DOOM_0", &player(4))
+            .send(&client, &addr)
+            .unwrap();
+        syn("SOME_FORK_7", &player(4))
+            .send(&network.bind_any(), &addr)
+            .unwrap();
+
+        for _ in 0..3 {
+            server.run();
+        }
+
+        assert_eq!(server.num_players(), 1);
+    }
+
+    #[test]
+    fn test_expand_tic_num() {
+        assert_eq!(expand_tic_num(0x1f0, 0x05), 0x205);
+        assert_eq!(expand_tic_num(0x205, 0xf0), 0x1f0);
+        assert_eq!(expand_tic_num(0x280, 0x90), 0x290);
+    }
+
+    #[test]
+    fn test_player_string_is_truncated() {
+        let buf = player_string(&"x".repeat(40));
+        assert_eq!(buf.iter().filter(|&&c| c == 'x').count(), MAXPLAYERNAME - 1);
+        assert_eq!(buf[MAXPLAYERNAME - 1], '\0');
+    }
+
+    /// Runs the clients, with the server running on its own thread, until
+    /// `step` reports the session has got where it should.
+    fn run_until(
+        server: &Mutex<NetServer>,
+        clients: &mut [NetClient],
+        timeout: Duration,
+        mut step: impl FnMut(&NetServer, &mut [NetClient]) -> bool,
+    ) {
+        let deadline = Instant::now() + timeout;
+        while !step(&server.lock().unwrap(), clients) {
+            assert!(
+                Instant::now() < deadline,
+                "Timed out waiting for the session"
+            );
+            for client in clients.iter_mut() {
+                client.run();
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    /// Runs `server` on its own thread until the returned flag is set.
+    fn spawn_server(
+        server: NetServer,
+    ) -> (
+        Arc<Mutex<NetServer>>,
+        Arc<AtomicBool>,
+        thread::JoinHandle<()>,
+    ) {
+        let server = Arc::new(Mutex::new(server));
+        let stop = Arc::new(AtomicBool::new(false));
+        let handle = {
+            let server = Arc::clone(&server);
+            let stop = Arc::clone(&stop);
+            thread::spawn(move || {
+                while !stop.load(Ordering::Relaxed) {
+                    server.lock().unwrap().run();
+                    thread::sleep(Duration::from_millis(1));
+                }
+            })
+        };
+
+        (server, stop, handle)
+    }
+
+    /// Plays a short two player game between `clients` through `server`.
+    fn play_session(server: NetServer, mut clients: [NetClient; 2], timeout: Duration) {
+        const TICS: u32 = 35;
+        const TIC_PERIOD: Duration = Duration::from_millis(28);
+        const MAX_LEAD: u32 = 8;
+
+        let (server, stop, handle) = spawn_server(server);
+        let addr = server.lock().unwrap().local_addr().unwrap();
+
+        for client in clients.iter_mut() {
+            client.init();
+            client.connect(addr, player(4)).unwrap();
+        }
+
+        let settings = GameSettings {
+            ticdup: 1,
+            extratics: 1,
+            skill: 2,
+            ..Default::default()
+        };
+        clients[0].launch_game().unwrap();
+        clients[0].start_game(&settings);
+        run_until(&server, &mut clients, timeout, |_, clients| {
+            clients.iter().all(|c| c.get_settings().is_some())
+        });
+
+        for (i, client) in clients.iter().enumerate() {
+            let settings = client.get_settings().unwrap();
+            assert_eq!(settings.num_players, 2);
+            assert_eq!(settings.consoleplayer, i as i32);
+            assert_eq!(
+                settings.prng_seed,
+                server.lock().unwrap().settings.prng_seed
+            );
+        }
+
+        // Make TICS tics, one every 1/35s like the game loop, without getting
+        // too far ahead of what has made it back to the clients. We are done
+        // once the server and every client have received all of them.
+        let mut maketic = 0;
+        let mut last_tic_time = Instant::now();
+        run_until(&server, &mut clients, timeout, |server, clients| {
+            let lowtic = clients.iter().map(NetClient::recv_tic).min().unwrap();
+            if server.recv_window_start == TICS && lowtic == TICS {
+                return true;
+            }
+
+            if maketic < TICS.min(lowtic + MAX_LEAD) && last_tic_time.elapsed() >= TIC_PERIOD {
+                let cmd = TicCmd {
+                    forwardmove: maketic as i8,
+                    ..Default::default()
+                };
+                for client in clients.iter_mut() {
+                    client.send_ticcmd(&cmd, maketic);
+                }
+                maketic += 1;
+                last_tic_time = Instant::now();
+            }
+
+            false
+        });
+
+        stop.store(true, Ordering::Relaxed);
+        handle.join().unwrap();
+
+        let server = server.lock().unwrap();
+        assert_eq!(server.state(), ServerState::InGame);
+        assert_eq!(server.recv_window_start, TICS);
+
+        // Bob was sent Alice's ticcmds, and the other way round.
+        let tic = &server.clients[1].send_queue[5];
+        assert_eq!(tic.seq, 5);
+        assert_eq!(tic.playeringame[..2], [true, true]);
+        assert_eq!(tic.cmds[0].cmd.forwardmove, 5);
+        assert_eq!(server.clients[0].send_queue[5].cmds[1].cmd.forwardmove, 5);
+    }
+
+    #[test]
+    fn test_full_session() {
+        play_session(
+            NetServer::new("127.0.0.1:0".parse().unwrap()).unwrap(),
+            [
+                NetClient::new("Alice".to_string(), false),
+                NetClient::new("Bob".to_string(), false),
+            ],
+            Duration::from_secs(10),
+        );
+    }
+
+    #[test]
+    fn test_full_session_over_loopback() {
+        let network = LoopbackNetwork::new();
+        let client = |name: &str| {
+            NetClient::with_transport(name.to_string(), false, Box::new(network.bind_any()))
+        };
+
+        play_session(
+            NetServer::with_transport(Box::new(network.bind_any())),
+            [client("Alice"), client("Bob")],
+            Duration::from_secs(10),
+        );
+    }
+
+    #[test]
+    fn test_connect_reports_version_mismatch() {
+        let (server, stop, handle) = spawn_server(local_server().0);
+        let server_addr = server.lock().unwrap().local_addr().unwrap();
+
+        // Relays between the client and the server, replacing the client's
+        // protocol list so the two have nothing in common.
+        let relay = UdpSocket::bind("127.0.0.1:0").unwrap();
+        relay
+            .set_read_timeout(Some(Duration::from_millis(10)))
+            .unwrap();
+        let relay_addr = relay.local_addr().unwrap();
+        let relay_stop = Arc::clone(&stop);
+        let relay_handle = thread::spawn(move || {
+            let mut client_addr = None;
+            while !relay_stop.load(Ordering::Relaxed) {
+                let Ok((mut packet, src)) = NetPacket::receive(&relay) else {
+                    continue;
+                };
+                if src == server_addr {
+                    if let Some(client_addr) = client_addr {
+                        packet.send(&relay, &client_addr).unwrap();
+                    }
+                    continue;
+                }
+
+                client_addr = Some(src);
+                if packet.read_u16() == Ok(NetPacketType::Syn as u16) {
+                    assert_eq!(packet.read_u32(), Ok(NET_MAGIC_NUMBER));
+                    packet.read_string().unwrap();
+                    packet.read_protocol_list();
+                    let data = packet.read_connect_data().unwrap();
+                    packet = syn("NOT_A_PROTOCOL", &data);
+                }
+                packet.send(&relay, &server_addr).unwrap();
+            }
+        });
+
+        let mut client = NetClient::new("Alice".to_string(), false);
+        client.set_connect_timeout(Duration::from_secs(5));
+        let result = client.connect(relay_addr, player(4));
+
+        stop.store(true, Ordering::Relaxed);
+        relay_handle.join().unwrap();
+        handle.join().unwrap();
+
+        assert!(matches!(
+            result,
+            Err(ConnectError::VersionMismatch { server_version })
+                if server_version == env!("CARGO_PKG_VERSION")
+        ));
+        assert_eq!(server.lock().unwrap().num_clients(), 0);
+    }
+
+    #[test]
+    fn test_controller_policy_launches_and_starts() {
+        let network = LoopbackNetwork::new();
+        let server = NetServer::with_transport(Box::new(network.bind_any()));
+        let (server, stop, handle) = spawn_server(server);
+        let addr = server.lock().unwrap().local_addr().unwrap();
+
+        let mut clients = ["Alice", "Bob", "Carol"].map(|name| {
+            NetClient::with_transport(name.to_string(), false, Box::new(network.bind_any()))
+        });
+        clients[0].set_controller_policy(Some(ControllerPolicy {
+            min_players: 3,
+            settings: GameSettings {
+                ticdup: 1,
+                skill: 3,
+                map: 7,
+                ..Default::default()
+            },
+        }));
+
+        // Nothing happens until enough players have joined.
+        for client in clients[..2].iter_mut() {
+            client.connect(addr, player(4)).unwrap();
+        }
+        let waited = Instant::now();
+        run_until(
+            &server,
+            &mut clients[..2],
+            Duration::from_secs(5),
+            |_, _| waited.elapsed() > Duration::from_millis(200),
+        );
+        assert_eq!(server.lock().unwrap().state(), ServerState::WaitingLaunch);
+
+        clients[2].connect(addr, player(4)).unwrap();
+        run_until(
+            &server,
+            &mut clients,
+            Duration::from_secs(5),
+            |_, clients| clients.iter().all(|c| c.get_settings().is_some()),
+        );
+
+        stop.store(true, Ordering::Relaxed);
+        handle.join().unwrap();
+
+        for client in &clients {
+            let settings = client.get_settings().unwrap();
+            assert_eq!(
+                (settings.num_players, settings.skill, settings.map),
+                (3, 3, 7)
+            );
+        }
+    }
+
+    #[test]
+    fn test_session_converges_on_a_bad_network() {
+        let network = LoopbackNetwork::new();
+        let impaired = |seed| {
+            let impairment = Impairment {
+                loss: 0.2,
+                latency: Duration::from_millis(20),
+                jitter: Duration::from_millis(150),
+                seed,
+                ..Default::default()
+            };
+            Box::new(ImpairedTransport::new(network.bind_any(), impairment))
+        };
+
+        play_session(
+            NetServer::with_transport(impaired(1)),
+            [
+                NetClient::with_transport("Alice".to_string(), false, impaired(2)),
+                NetClient::with_transport("Bob".to_string(), false, impaired(3)),
+            ],
+            Duration::from_secs(60),
+        );
+    }
+}
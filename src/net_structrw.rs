@@ -0,0 +1,741 @@
+use crate::net_packet::{FieldContext, NetPacket, PacketError};
+use crate::net_structs::*;
+
+/// A structure that can be written to and read back from a packet.
+///
+/// Every implementation matches the encoding in Chocolate Doom's
+/// net_structrw.c byte for byte, so the same codec serves both ends of a
+/// connection.
+pub trait Wire: Sized {
+    /// Extra information the encoding depends on, such as whether turns
+    /// are sent at low resolution.
+    type Context: Copy;
+
+    fn write(&self, packet: &mut NetPacket, ctx: Self::Context);
+
+    fn read(packet: &mut NetPacket, ctx: Self::Context) -> Result<Self, PacketError>;
+}
+
+/// Reads a player count, which must fit in our per-player arrays.
+fn read_player_count(packet: &mut NetPacket, field: &'static str) -> Result<i32, PacketError> {
+    let offset = packet.pos;
+    let count = packet.read_u8().field(field)? as usize;
+    if count > NET_MAXPLAYERS {
+        return Err(PacketError::invalid(field, offset));
+    }
+    Ok(count as i32)
+}
+
+/// Reads a string that must fit in a `MAXPLAYERNAME` buffer along with
+/// its terminator.
+fn read_player_string(
+    packet: &mut NetPacket,
+    field: &'static str,
+) -> Result<[char; MAXPLAYERNAME], PacketError> {
+    let offset = packet.pos;
+    let string = packet.read_string().field(field)?;
+    if string.len() >= MAXPLAYERNAME {
+        return Err(PacketError::invalid(field, offset));
+    }
+
+    let mut buf = ['\0'; MAXPLAYERNAME];
+    for (c, slot) in string.chars().zip(buf.iter_mut()) {
+        *slot = c;
+    }
+    Ok(buf)
+}
+
+fn write_player_string(packet: &mut NetPacket, buf: &[char; MAXPLAYERNAME]) {
+    let string: String = buf.iter().take_while(|&&c| c != '\0').collect();
+    packet.write_string(&string);
+}
+
+impl Wire for ConnectData {
+    type Context = ();
+
+    fn write(&self, packet: &mut NetPacket, _: ()) {
+        packet.write_u8(self.gamemode as u8);
+        packet.write_u8(self.gamemission as u8);
+        packet.write_u8(self.lowres_turn as u8);
+        packet.write_u8(self.drone as u8);
+        packet.write_u8(self.max_players as u8);
+        packet.write_u8(self.is_freedoom as u8);
+        packet.write_sha1sum(&self.wad_sha1sum);
+        packet.write_sha1sum(&self.deh_sha1sum);
+        packet.write_u8(self.player_class as u8);
+    }
+
+    fn read(packet: &mut NetPacket, _: ()) -> Result<Self, PacketError> {
+        Ok(ConnectData {
+            gamemode: packet.read_u8().field("connect.gamemode")? as i32,
+            gamemission: packet.read_u8().field("connect.gamemission")? as i32,
+            lowres_turn: packet.read_u8().field("connect.lowres_turn")? as i32,
+            drone: packet.read_u8().field("connect.drone")? as i32,
+            max_players: packet.read_u8().field("connect.max_players")? as i32,
+            is_freedoom: packet.read_u8().field("connect.is_freedoom")? as i32,
+            wad_sha1sum: packet.read_sha1sum("connect.wad_sha1sum")?,
+            deh_sha1sum: packet.read_sha1sum("connect.deh_sha1sum")?,
+            player_class: packet.read_u8().field("connect.player_class")? as i32,
+        })
+    }
+}
+
+impl Wire for GameSettings {
+    type Context = ();
+
+    fn write(&self, packet: &mut NetPacket, _: ()) {
+        packet.write_u8(self.ticdup as u8);
+        packet.write_u8(self.extratics as u8);
+        packet.write_u8(self.deathmatch as u8);
+        packet.write_u8(self.nomonsters as u8);
+        packet.write_u8(self.fast_monsters as u8);
+        packet.write_u8(self.respawn_monsters as u8);
+        packet.write_u8(self.episode as u8);
+        packet.write_u8(self.map as u8);
+        packet.write_i8(self.skill as i8);
+        packet.write_u8(self.gameversion as u8);
+        packet.write_u8(self.lowres_turn as u8);
+        packet.write_u8(self.new_sync as u8);
+        packet.write_u32(self.timelimit);
+        packet.write_i8(self.loadgame as i8);
+        packet.write_u8(self.random as u8);
+        packet.write_u8(self.num_players as u8);
+        packet.write_i8(self.consoleplayer as i8);
+        for class in self.player_classes.iter().take(self.num_players as usize) {
+            packet.write_u8(*class as u8);
+        }
+    }
+
+    /// The PRNG seed is not part of the settings on the wire; see
+    /// [`NetPacket::read_settings_and_seed`].
+    fn read(packet: &mut NetPacket, _: ()) -> Result<Self, PacketError> {
+        let mut settings = GameSettings {
+            ticdup: packet.read_u8().field("settings.ticdup")? as i32,
+            extratics: packet.read_u8().field("settings.extratics")? as i32,
+            deathmatch: packet.read_u8().field("settings.deathmatch")? as i32,
+            nomonsters: packet.read_u8().field("settings.nomonsters")? as i32,
+            fast_monsters: packet.read_u8().field("settings.fast_monsters")? as i32,
+            respawn_monsters: packet.read_u8().field("settings.respawn_monsters")? as i32,
+            episode: packet.read_u8().field("settings.episode")? as i32,
+            map: packet.read_u8().field("settings.map")? as i32,
+            skill: packet.read_i8().field("settings.skill")? as i32,
+            gameversion: packet.read_u8().field("settings.gameversion")? as i32,
+            lowres_turn: packet.read_u8().field("settings.lowres_turn")? as i32,
+            new_sync: packet.read_u8().field("settings.new_sync")? as i32,
+            timelimit: packet.read_u32().field("settings.timelimit")?,
+            loadgame: packet.read_i8().field("settings.loadgame")? as i32,
+            random: packet.read_u8().field("settings.random")? as i32,
+            num_players: read_player_count(packet, "settings.num_players")?,
+            consoleplayer: packet.read_i8().field("settings.consoleplayer")? as i32,
+            player_classes: [0; NET_MAXPLAYERS],
+            prng_seed: [0; PRNG_SEED_SIZE],
+        };
+
+        let num_players = settings.num_players as usize;
+        for class in settings.player_classes.iter_mut().take(num_players) {
+            *class = packet.read_u8().field("settings.player_class")? as i32;
+        }
+
+        Ok(settings)
+    }
+}
+
+impl Wire for NetQueryData {
+    type Context = ();
+
+    /// The `protocol` field is ignored; the list of all protocols we
+    /// support is written instead.
+    fn write(&self, packet: &mut NetPacket, _: ()) {
+        packet.write_string(&self.version);
+        packet.write_u8(self.server_state as u8);
+        packet.write_u8(self.num_players as u8);
+        packet.write_u8(self.max_players as u8);
+        packet.write_u8(self.gamemode as u8);
+        packet.write_u8(self.gamemission as u8);
+        packet.write_string(&self.description);
+        packet.write_protocol_list();
+    }
+
+    fn read(packet: &mut NetPacket, _: ()) -> Result<Self, PacketError> {
+        Ok(NetQueryData {
+            version: packet.read_safe_string().field("query.version")?,
+            server_state: packet.read_u8().field("query.server_state")? as i32,
+            num_players: packet.read_u8().field("query.num_players")? as i32,
+            max_players: packet.read_u8().field("query.max_players")? as i32,
+            gamemode: packet.read_u8().field("query.gamemode")? as i32,
+            gamemission: packet.read_u8().field("query.gamemission")? as i32,
+            description: packet.read_safe_string().field("query.description")?,
+            // Old versions of Chocolate Doom do not send the protocol list;
+            // it is okay if it cannot be read.
+            protocol: packet.read_protocol_list(),
+        })
+    }
+}
+
+impl Wire for NetTicDiff {
+    /// Whether turns are sent as a single byte.
+    type Context = bool;
+
+    fn write(&self, packet: &mut NetPacket, lowres_turn: bool) {
+        packet.write_u8(self.diff as u8);
+
+        if self.diff & NET_TICDIFF_FORWARD != 0 {
+            packet.write_i8(self.cmd.forwardmove);
+        }
+
+        if self.diff & NET_TICDIFF_SIDE != 0 {
+            packet.write_i8(self.cmd.sidemove);
+        }
+
+        if self.diff & NET_TICDIFF_TURN != 0 {
+            if lowres_turn {
+                packet.write_i8((self.cmd.angleturn / 256) as i8);
+            } else {
+                packet.write_i16(self.cmd.angleturn);
+            }
+        }
+
+        if self.diff & NET_TICDIFF_BUTTONS != 0 {
+            packet.write_u8(self.cmd.buttons);
+        }
+
+        if self.diff & NET_TICDIFF_CONSISTANCY != 0 {
+            packet.write_u8(self.cmd.consistancy);
+        }
+
+        if self.diff & NET_TICDIFF_CHATCHAR != 0 {
+            packet.write_u8(self.cmd.chatchar);
+        }
+
+        if self.diff & NET_TICDIFF_RAVEN != 0 {
+            packet.write_u8(self.cmd.lookfly);
+            packet.write_u8(self.cmd.arti);
+        }
+
+        if self.diff & NET_TICDIFF_STRIFE != 0 {
+            packet.write_u8(self.cmd.buttons2);
+            packet.write_u16(self.cmd.inventory as u16);
+        }
+    }
+
+    fn read(packet: &mut NetPacket, lowres_turn: bool) -> Result<Self, PacketError> {
+        let mut diff = NetTicDiff {
+            diff: packet.read_u8().field("ticdiff.diff")? as u32,
+            cmd: TicCmd::default(),
+        };
+
+        if diff.diff & NET_TICDIFF_FORWARD != 0 {
+            diff.cmd.forwardmove = packet.read_i8().field("ticcmd.forwardmove")?;
+        }
+
+        if diff.diff & NET_TICDIFF_SIDE != 0 {
+            diff.cmd.sidemove = packet.read_i8().field("ticcmd.sidemove")?;
+        }
+
+        if diff.diff & NET_TICDIFF_TURN != 0 {
+            if lowres_turn {
+                diff.cmd.angleturn = (packet.read_i8().field("ticcmd.angleturn")? as i16) * 256;
+            } else {
+                diff.cmd.angleturn = packet.read_i16().field("ticcmd.angleturn")?;
+            }
+        }
+
+        if diff.diff & NET_TICDIFF_BUTTONS != 0 {
+            diff.cmd.buttons = packet.read_u8().field("ticcmd.buttons")?;
+        }
+
+        if diff.diff & NET_TICDIFF_CONSISTANCY != 0 {
+            diff.cmd.consistancy = packet.read_u8().field("ticcmd.consistancy")?;
+        }
+
+        if diff.diff & NET_TICDIFF_CHATCHAR != 0 {
+            diff.cmd.chatchar = packet.read_u8().field("ticcmd.chatchar")?;
+        }
+
+        if diff.diff & NET_TICDIFF_RAVEN != 0 {
+            diff.cmd.lookfly = packet.read_u8().field("ticcmd.lookfly")?;
+            diff.cmd.arti = packet.read_u8().field("ticcmd.arti")?;
+        }
+
+        if diff.diff & NET_TICDIFF_STRIFE != 0 {
+            diff.cmd.buttons2 = packet.read_u8().field("ticcmd.buttons2")?;
+            diff.cmd.inventory = packet.read_u16().field("ticcmd.inventory")? as i32;
+        }
+
+        Ok(diff)
+    }
+}
+
+impl Wire for NetFullTicCmd {
+    /// Whether turns are sent as a single byte.
+    type Context = bool;
+
+    fn write(&self, packet: &mut NetPacket, lowres_turn: bool) {
+        packet.write_i16(self.latency as i16);
+
+        // Header byte indicating which players are active in this ticcmd
+        let bitfield = self
+            .playeringame
+            .iter()
+            .enumerate()
+            .filter(|(_, &ingame)| ingame)
+            .fold(0u8, |bits, (i, _)| bits | (1 << i));
+        packet.write_u8(bitfield);
+
+        for (cmd, _) in self
+            .cmds
+            .iter()
+            .zip(self.playeringame)
+            .filter(|(_, ingame)| *ingame)
+        {
+            cmd.write(packet, lowres_turn);
+        }
+    }
+
+    fn read(packet: &mut NetPacket, lowres_turn: bool) -> Result<Self, PacketError> {
+        let mut cmd = NetFullTicCmd {
+            latency: packet.read_i16().field("ticcmd.latency")? as i32,
+            ..Default::default()
+        };
+
+        // Regenerate playeringame from the header bitfield
+        let bitfield = packet.read_u8().field("ticcmd.playeringame")?;
+        for (i, ingame) in cmd.playeringame.iter_mut().enumerate() {
+            *ingame = bitfield & (1 << i) != 0;
+        }
+
+        for (diff, ingame) in cmd.cmds.iter_mut().zip(cmd.playeringame) {
+            if ingame {
+                *diff = NetTicDiff::read(packet, lowres_turn)?;
+            }
+        }
+        Ok(cmd)
+    }
+}
+
+impl Wire for NetWaitData {
+    type Context = ();
+
+    fn write(&self, packet: &mut NetPacket, _: ()) {
+        packet.write_u8(self.num_players as u8);
+        packet.write_u8(self.num_drones as u8);
+        packet.write_u8(self.ready_players as u8);
+        packet.write_u8(self.max_players as u8);
+        packet.write_u8(self.is_controller as u8);
+        packet.write_i8(self.consoleplayer as i8);
+
+        let num_players = self.num_players as usize;
+        for (name, addr) in self
+            .player_names
+            .iter()
+            .zip(&self.player_addrs)
+            .take(num_players)
+        {
+            write_player_string(packet, name);
+            write_player_string(packet, addr);
+        }
+
+        packet.write_sha1sum(&self.wad_sha1sum);
+        packet.write_sha1sum(&self.deh_sha1sum);
+        packet.write_u8(self.is_freedoom as u8);
+    }
+
+    fn read(packet: &mut NetPacket, _: ()) -> Result<Self, PacketError> {
+        let mut data = NetWaitData {
+            num_players: read_player_count(packet, "wait.num_players")?,
+            num_drones: packet.read_u8().field("wait.num_drones")? as i32,
+            ready_players: packet.read_u8().field("wait.ready_players")? as i32,
+            max_players: packet.read_u8().field("wait.max_players")? as i32,
+            is_controller: packet.read_u8().field("wait.is_controller")? as i32,
+            consoleplayer: packet.read_i8().field("wait.consoleplayer")? as i32,
+            ..Default::default()
+        };
+
+        for i in 0..data.num_players as usize {
+            data.player_names[i] = read_player_string(packet, "wait.player_name")?;
+            data.player_addrs[i] = read_player_string(packet, "wait.player_addr")?;
+        }
+
+        data.wad_sha1sum = packet.read_sha1sum("wait.wad_sha1sum")?;
+        data.deh_sha1sum = packet.read_sha1sum("wait.deh_sha1sum")?;
+        data.is_freedoom = packet.read_u8().field("wait.is_freedoom")? as i32;
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn round_trip<T: Wire>(value: &T, ctx: T::Context) -> (Result<T, PacketError>, NetPacket) {
+        let mut packet = NetPacket::new();
+        value.write(&mut packet, ctx);
+        let result = T::read(&mut packet, ctx);
+        (result, packet)
+    }
+
+    fn player_string(s: &str) -> [char; MAXPLAYERNAME] {
+        let mut buf = ['\0'; MAXPLAYERNAME];
+        for (c, slot) in s.chars().zip(buf.iter_mut()) {
+            *slot = c;
+        }
+        buf
+    }
+
+    fn wait_data(num_players: usize) -> NetWaitData {
+        let mut data = NetWaitData {
+            num_players: num_players as i32,
+            ready_players: num_players as i32,
+            max_players: 4,
+            is_controller: 1,
+            wad_sha1sum: [0xaa; 20],
+            deh_sha1sum: [0xbb; 20],
+            is_freedoom: 1,
+            ..Default::default()
+        };
+        for i in 0..num_players {
+            data.player_names[i] = player_string(&format!("Player{}", i + 1));
+            data.player_addrs[i] = player_string("127.0.0.1");
+        }
+        data
+    }
+
+    #[test]
+    fn test_connect_data_layout() {
+        let data = ConnectData {
+            gamemode: 2,
+            gamemission: 1,
+            lowres_turn: 0,
+            drone: 1,
+            max_players: 4,
+            is_freedoom: 0,
+            wad_sha1sum: [0x11; 20],
+            deh_sha1sum: [0x22; 20],
+            player_class: 3,
+        };
+
+        let mut packet = NetPacket::new();
+        data.write(&mut packet, ());
+
+        let mut expected = vec![2, 1, 0, 1, 4, 0];
+        expected.extend_from_slice(&[0x11; 20]);
+        expected.extend_from_slice(&[0x22; 20]);
+        expected.push(3);
+        assert_eq!(packet.data, expected);
+
+        assert_eq!(ConnectData::read(&mut packet, ()), Ok(data));
+    }
+
+    #[test]
+    fn test_full_ticcmd_layout() {
+        let mut cmd = NetFullTicCmd {
+            latency: -2,
+            ..Default::default()
+        };
+        cmd.playeringame[1] = true;
+        cmd.cmds[1] = NetTicDiff {
+            diff: NET_TICDIFF_FORWARD | NET_TICDIFF_TURN,
+            cmd: TicCmd {
+                forwardmove: 25,
+                angleturn: 0x0300,
+                ..Default::default()
+            },
+        };
+
+        let mut packet = NetPacket::new();
+        cmd.write(&mut packet, true);
+        assert_eq!(
+            packet.data,
+            [
+                0xff,
+                0xfe,
+                0b10,
+                (NET_TICDIFF_FORWARD | NET_TICDIFF_TURN) as u8,
+                25,
+                3
+            ]
+        );
+
+        assert_eq!(NetFullTicCmd::read(&mut packet, true), Ok(cmd));
+    }
+
+    #[test]
+    fn test_wait_data_round_trip() {
+        let data = wait_data(2);
+        let (result, packet) = round_trip(&data, ());
+
+        assert_eq!(result, Ok(data));
+        assert_eq!(packet.pos, packet.data.len());
+    }
+
+    #[test]
+    fn test_read_wait_data_rejects_bad_lengths() {
+        let mut packet = NetPacket::new();
+        wait_data(2).write(&mut packet, ());
+        packet.data.truncate(packet.data.len() - 10);
+        let err = NetWaitData::read(&mut packet, ()).unwrap_err();
+        assert_eq!(err.field, "wait.deh_sha1sum");
+
+        let mut packet = NetPacket::new();
+        wait_data(0).write(&mut packet, ());
+        packet.data[0] = NET_MAXPLAYERS as u8 + 1;
+        let err = NetWaitData::read(&mut packet, ()).unwrap_err();
+        assert_eq!(err.field, "wait.num_players");
+        assert_eq!(err.kind, crate::net_packet::PacketErrorKind::Invalid);
+    }
+
+    #[test]
+    fn test_read_settings_rejects_too_many_players() {
+        let mut packet = NetPacket::new();
+        GameSettings::default().write(&mut packet, ());
+        packet.data[18] = NET_MAXPLAYERS as u8 + 1; // num_players
+        packet.data.extend_from_slice(&[0; 16]);
+
+        let err = GameSettings::read(&mut packet, ()).unwrap_err();
+        assert_eq!(err.field, "settings.num_players");
+        assert_eq!(err.offset, 18);
+    }
+
+    #[test]
+    fn test_read_query_data_strips_unprintable_characters() {
+        let query = NetQueryData {
+            version: "Chocolate\x07 Doom".to_string(),
+            description: "Line one\nLine two\x1b".to_string(),
+            ..Default::default()
+        };
+
+        let (result, _) = round_trip(&query, ());
+        let result = result.unwrap();
+        assert_eq!(result.version, "Chocolate Doom");
+        assert_eq!(result.description, "Line one\nLine two");
+    }
+
+    fn ticdiff_strategy(lowres_turn: bool) -> impl Strategy<Value = NetTicDiff> {
+        (
+            any::<u8>(),
+            any::<(i8, i8, i16, u8, u8)>(),
+            any::<(u8, u8, u8, u8, u16)>(),
+        )
+            .prop_map(move |(diff, first, second)| {
+                let (forwardmove, sidemove, angleturn, buttons, consistancy) = first;
+                let (chatchar, lookfly, arti, buttons2, inventory) = second;
+                let diff = diff as u32;
+                let has = |flag| diff & flag != 0;
+
+                // Only the fields selected by the diff go over the wire
+                let mut cmd = TicCmd::default();
+                if has(NET_TICDIFF_FORWARD) {
+                    cmd.forwardmove = forwardmove;
+                }
+                if has(NET_TICDIFF_SIDE) {
+                    cmd.sidemove = sidemove;
+                }
+                if has(NET_TICDIFF_TURN) {
+                    cmd.angleturn = if lowres_turn {
+                        angleturn & !0xff
+                    } else {
+                        angleturn
+                    };
+                }
+                if has(NET_TICDIFF_BUTTONS) {
+                    cmd.buttons = buttons;
+                }
+                if has(NET_TICDIFF_CONSISTANCY) {
+                    cmd.consistancy = consistancy;
+                }
+                if has(NET_TICDIFF_CHATCHAR) {
+                    cmd.chatchar = chatchar;
+                }
+                if has(NET_TICDIFF_RAVEN) {
+                    cmd.lookfly = lookfly;
+                    cmd.arti = arti;
+                }
+                if has(NET_TICDIFF_STRIFE) {
+                    cmd.buttons2 = buttons2;
+                    cmd.inventory = inventory as i32;
+                }
+
+                NetTicDiff { diff, cmd }
+            })
+    }
+
+    fn full_ticcmd_strategy(lowres_turn: bool) -> impl Strategy<Value = NetFullTicCmd> {
+        (
+            any::<i16>(),
+            any::<[bool; NET_MAXPLAYERS]>(),
+            prop::array::uniform8(ticdiff_strategy(lowres_turn)),
+        )
+            .prop_map(|(latency, playeringame, cmds)| NetFullTicCmd {
+                latency: latency as i32,
+                seq: 0,
+                playeringame,
+                // Commands of absent players are not sent
+                cmds: std::array::from_fn(|i| {
+                    if playeringame[i] {
+                        cmds[i]
+                    } else {
+                        NetTicDiff::default()
+                    }
+                }),
+            })
+    }
+
+    fn settings_strategy() -> impl Strategy<Value = GameSettings> {
+        (
+            any::<[u8; 12]>(),
+            any::<(u32, i8, u8, i8)>(),
+            0..=NET_MAXPLAYERS,
+            any::<[u8; NET_MAXPLAYERS]>(),
+        )
+            .prop_map(
+                |(bytes, (timelimit, loadgame, random, consoleplayer), num_players, classes)| {
+                    GameSettings {
+                        ticdup: bytes[0] as i32,
+                        extratics: bytes[1] as i32,
+                        deathmatch: bytes[2] as i32,
+                        nomonsters: bytes[3] as i32,
+                        fast_monsters: bytes[4] as i32,
+                        respawn_monsters: bytes[5] as i32,
+                        episode: bytes[6] as i32,
+                        map: bytes[7] as i32,
+                        skill: bytes[8] as i8 as i32,
+                        gameversion: bytes[9] as i32,
+                        lowres_turn: bytes[10] as i32,
+                        new_sync: bytes[11] as i32,
+                        timelimit,
+                        loadgame: loadgame as i32,
+                        random: random as i32,
+                        num_players: num_players as i32,
+                        consoleplayer: consoleplayer as i32,
+                        player_classes: std::array::from_fn(|i| {
+                            if i < num_players {
+                                classes[i] as i32
+                            } else {
+                                0
+                            }
+                        }),
+                        prng_seed: [0; PRNG_SEED_SIZE],
+                    }
+                },
+            )
+    }
+
+    fn player_string_strategy() -> impl Strategy<Value = [char; MAXPLAYERNAME]> {
+        "[ -~]{0,29}".prop_map(|s| player_string(&s))
+    }
+
+    fn wait_data_strategy() -> impl Strategy<Value = NetWaitData> {
+        (
+            0..=NET_MAXPLAYERS,
+            any::<(u8, u8, u8, u8, i8)>(),
+            prop::array::uniform8((player_string_strategy(), player_string_strategy())),
+            any::<([u8; 20], [u8; 20], u8)>(),
+        )
+            .prop_map(|(num_players, header, players, trailer)| {
+                let (num_drones, ready_players, max_players, is_controller, consoleplayer) = header;
+                let (wad_sha1sum, deh_sha1sum, is_freedoom) = trailer;
+                let player = |i: usize| {
+                    if i < num_players {
+                        players[i]
+                    } else {
+                        (['\0'; MAXPLAYERNAME], ['\0'; MAXPLAYERNAME])
+                    }
+                };
+
+                NetWaitData {
+                    num_players: num_players as i32,
+                    num_drones: num_drones as i32,
+                    ready_players: ready_players as i32,
+                    max_players: max_players as i32,
+                    is_controller: is_controller as i32,
+                    consoleplayer: consoleplayer as i32,
+                    player_names: std::array::from_fn(|i| player(i).0),
+                    player_addrs: std::array::from_fn(|i| player(i).1),
+                    wad_sha1sum,
+                    deh_sha1sum,
+                    is_freedoom: is_freedoom as i32,
+                }
+            })
+    }
+
+    proptest! {
+        #[test]
+        fn prop_connect_data_round_trip(
+            bytes in any::<[u8; 7]>(),
+            wad_sha1sum in any::<[u8; 20]>(),
+            deh_sha1sum in any::<[u8; 20]>(),
+        ) {
+            let data = ConnectData {
+                gamemode: bytes[0] as i32,
+                gamemission: bytes[1] as i32,
+                lowres_turn: bytes[2] as i32,
+                drone: bytes[3] as i32,
+                max_players: bytes[4] as i32,
+                is_freedoom: bytes[5] as i32,
+                wad_sha1sum,
+                deh_sha1sum,
+                player_class: bytes[6] as i32,
+            };
+
+            let (result, packet) = round_trip(&data, ());
+            prop_assert_eq!(result, Ok(data));
+            prop_assert_eq!(packet.data.len(), 47);
+        }
+
+        #[test]
+        fn prop_settings_round_trip(settings in settings_strategy()) {
+            let (result, packet) = round_trip(&settings, ());
+            prop_assert_eq!(result, Ok(settings));
+            prop_assert_eq!(packet.pos, packet.data.len());
+        }
+
+        #[test]
+        fn prop_query_data_round_trip(
+            version in "[ -~\n]{0,32}",
+            description in "[ -~\n]{0,64}",
+            fields in any::<[u8; 5]>(),
+        ) {
+            let query = NetQueryData {
+                version,
+                server_state: fields[0] as i32,
+                num_players: fields[1] as i32,
+                max_players: fields[2] as i32,
+                gamemode: fields[3] as i32,
+                gamemission: fields[4] as i32,
+                description,
+                protocol: NetProtocol::ChocolateDoom0,
+            };
+
+            let (result, _) = round_trip(&query, ());
+            prop_assert_eq!(result, Ok(query));
+        }
+
+        #[test]
+        fn prop_ticcmd_diff_round_trip(
+            (lowres_turn, diff) in any::<bool>()
+                .prop_flat_map(|lowres| (Just(lowres), ticdiff_strategy(lowres)))
+        ) {
+            let (result, packet) = round_trip(&diff, lowres_turn);
+            prop_assert_eq!(result, Ok(diff));
+            prop_assert_eq!(packet.pos, packet.data.len());
+        }
+
+        #[test]
+        fn prop_full_ticcmd_round_trip(
+            (lowres_turn, cmd) in any::<bool>()
+                .prop_flat_map(|lowres| (Just(lowres), full_ticcmd_strategy(lowres)))
+        ) {
+            let (result, packet) = round_trip(&cmd, lowres_turn);
+            prop_assert_eq!(result, Ok(cmd));
+            prop_assert_eq!(packet.pos, packet.data.len());
+        }
+
+        #[test]
+        fn prop_wait_data_round_trip(data in wait_data_strategy()) {
+            let (result, packet) = round_trip(&data, ());
+            prop_assert_eq!(result, Ok(data));
+            prop_assert_eq!(packet.pos, packet.data.len());
+        }
+    }
+}
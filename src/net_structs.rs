@@ -1,4 +1,6 @@
 use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fmt;
 use std::net::SocketAddr;
 use std::time::Instant;
 
@@ -8,6 +10,16 @@ pub const NET_MAXPLAYERS: usize = 8;
 pub const MAXPLAYERNAME: usize = 30;
 pub const BACKUPTICS: usize = 128;
 pub const NET_MAGIC_NUMBER: u32 = 1454104972;
+pub const PRNG_SEED_SIZE: usize = 16;
+
+/// Port Chocolate Doom servers listen on by default.
+pub const DEFAULT_PORT: u16 = 2342;
+
+// Old magic number used by Chocolate Doom versions before v3.0
+pub const NET_OLD_MAGIC_NUMBER: u32 = 3436803284;
+
+// Header field value indicating that the packet is a reliable packet
+pub const NET_RELIABLE_PACKET: u16 = 1 << 15;
 
 // TicDiff Flags
 pub const NET_TICDIFF_FORWARD: u32 = 1 << 0;
@@ -19,7 +31,7 @@ pub const NET_TICDIFF_CHATCHAR: u32 = 1 << 5;
 pub const NET_TICDIFF_RAVEN: u32 = 1 << 6;
 pub const NET_TICDIFF_STRIFE: u32 = 1 << 7;
 
-#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub struct TicCmd {
     pub forwardmove: i8,
     pub sidemove: i8,
@@ -33,7 +45,7 @@ pub struct TicCmd {
     pub arti: u8,
 }
 
-#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub struct ConnectData {
     pub gamemode: i32,
     pub gamemission: i32,
@@ -46,7 +58,7 @@ pub struct ConnectData {
     pub player_class: i32,
 }
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize, Default)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Default)]
 pub struct GameSettings {
     pub ticdup: i32,
     pub extratics: i32,
@@ -66,6 +78,9 @@ pub struct GameSettings {
     pub num_players: i32,
     pub consoleplayer: i32,
     pub player_classes: [i32; NET_MAXPLAYERS],
+    /// Seed for the random number generator every player shares, chosen
+    /// by the server. All zeros from servers that do not send one.
+    pub prng_seed: [u8; PRNG_SEED_SIZE],
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
@@ -86,6 +101,7 @@ pub enum NetPacketType {
     Query,
     QueryResponse,
     Launch,
+    NatHolePunch,
 }
 
 impl TryFrom<u16> for NetPacketType {
@@ -109,18 +125,154 @@ impl TryFrom<u16> for NetPacketType {
             13 => Ok(NetPacketType::Query),
             14 => Ok(NetPacketType::QueryResponse),
             15 => Ok(NetPacketType::Launch),
+            16 => Ok(NetPacketType::NatHolePunch),
             _ => Err(()),
         }
     }
 }
 
-#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum NetMasterPacketType {
+    Add,
+    AddResponse,
+    Query,
+    QueryResponse,
+    GetMetadata,
+    GetMetadataResponse,
+    SignStart,
+    SignStartResponse,
+    SignEnd,
+    SignEndResponse,
+    NatHolePunch,
+    NatHolePunchAll,
+}
+
+impl TryFrom<u16> for NetMasterPacketType {
+    type Error = ();
+
+    fn try_from(value: u16) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(NetMasterPacketType::Add),
+            1 => Ok(NetMasterPacketType::AddResponse),
+            2 => Ok(NetMasterPacketType::Query),
+            3 => Ok(NetMasterPacketType::QueryResponse),
+            4 => Ok(NetMasterPacketType::GetMetadata),
+            5 => Ok(NetMasterPacketType::GetMetadataResponse),
+            6 => Ok(NetMasterPacketType::SignStart),
+            7 => Ok(NetMasterPacketType::SignStartResponse),
+            8 => Ok(NetMasterPacketType::SignEnd),
+            9 => Ok(NetMasterPacketType::SignEndResponse),
+            10 => Ok(NetMasterPacketType::NatHolePunch),
+            11 => Ok(NetMasterPacketType::NatHolePunchAll),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Network protocols that can be negotiated between client and server.
+/// Only the name matters on the wire; see `NetProtocol::name`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum NetProtocol {
+    /// Protocol introduced with Chocolate Doom v3.0
+    ChocolateDoom0,
+    #[default]
+    Unknown,
+}
+
+impl NetProtocol {
+    /// Protocols we support, in order of increasing preference.
+    pub const SUPPORTED: [NetProtocol; 1] = [NetProtocol::ChocolateDoom0];
+
+    /// Returns the name used for this protocol on the wire.
+    pub fn name(self) -> Option<&'static str> {
+        match self {
+            NetProtocol::ChocolateDoom0 => Some("CHOCOLATE_DOOM_0"),
+            NetProtocol::Unknown => None,
+        }
+    }
+
+    /// Parses a protocol name, returning `Unknown` for names we do not speak.
+    pub fn from_name(name: &str) -> Self {
+        Self::SUPPORTED
+            .into_iter()
+            .find(|p| p.name() == Some(name))
+            .unwrap_or(NetProtocol::Unknown)
+    }
+}
+
+// Reject sent by servers before v3.0 to clients of another version, and by
+// v3.0 and later servers that share no protocol with the client; the latter
+// append the client's version after the separator below.
+const VERSION_MISMATCH_PREFIX: &str = "Version mismatch: server version is: ";
+const VERSION_MISMATCH_CLIENT_SEPARATOR: &str = "; client is: ";
+
+// Reject sent by v3.0 and later servers to clients using the old handshake
+const OLD_CLIENT_PREFIX: &str =
+    "You are using an old client version that is not supported by this server. \
+    This server is running ";
+const OLD_CLIENT_SUFFIX: &str = ".";
+
+/// A reject in one of the fixed formats Chocolate Doom servers use when
+/// the client's version is the problem.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VersionReject {
+    /// The server cannot talk to a client of this version
+    VersionMismatch { server_version: String },
+    /// A v3.0 or later server does not accept the old handshake
+    OldClient { server_version: String },
+}
+
+impl VersionReject {
+    /// Parses a reject message, returning `None` for any other reject.
+    pub fn parse(reason: &str) -> Option<Self> {
+        if let Some(rest) = reason.strip_prefix(VERSION_MISMATCH_PREFIX) {
+            let server_version = rest
+                .split(VERSION_MISMATCH_CLIENT_SEPARATOR)
+                .next()
+                .unwrap_or(rest);
+            return (!server_version.is_empty()).then(|| VersionReject::VersionMismatch {
+                server_version: server_version.to_string(),
+            });
+        }
+
+        let server_version = reason
+            .strip_prefix(OLD_CLIENT_PREFIX)?
+            .strip_suffix(OLD_CLIENT_SUFFIX)?;
+        (!server_version.is_empty()).then(|| VersionReject::OldClient {
+            server_version: server_version.to_string(),
+        })
+    }
+
+    pub fn server_version(&self) -> &str {
+        match self {
+            VersionReject::VersionMismatch { server_version }
+            | VersionReject::OldClient { server_version } => server_version,
+        }
+    }
+}
+
+impl fmt::Display for VersionReject {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VersionReject::VersionMismatch { server_version } => {
+                write!(f, "{}{}", VERSION_MISMATCH_PREFIX, server_version)
+            }
+            VersionReject::OldClient { server_version } => write!(
+                f,
+                "{}{}{}",
+                OLD_CLIENT_PREFIX, server_version, OLD_CLIENT_SUFFIX
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub struct NetTicDiff {
     pub diff: u32,
     pub cmd: TicCmd,
 }
 
-#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub struct NetFullTicCmd {
     pub latency: i32,
     pub seq: u32,
@@ -128,7 +280,7 @@ pub struct NetFullTicCmd {
     pub cmds: [NetTicDiff; NET_MAXPLAYERS],
 }
 
-#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
 pub struct NetQueryData {
     pub version: String,
     pub server_state: i32,
@@ -137,9 +289,10 @@ pub struct NetQueryData {
     pub gamemode: i32,
     pub gamemission: i32,
     pub description: String,
+    pub protocol: NetProtocol,
 }
 
-#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub struct NetWaitData {
     pub num_players: i32,
     pub num_drones: i32,
@@ -154,6 +307,62 @@ pub struct NetWaitData {
     pub is_freedoom: i32,
 }
 
+/// A player in the lobby, as the server last described it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LobbyPlayer {
+    pub name: String,
+    pub address: String,
+}
+
+/// The lobby as seen in the last `NetWaitData`, with the player strings
+/// turned into `String`s.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LobbyState {
+    /// Players in the order of their player numbers
+    pub players: Vec<LobbyPlayer>,
+    /// How many of the players are ready to play. The protocol only
+    /// carries the count, not which players they are.
+    pub ready_players: usize,
+    pub num_drones: usize,
+    pub max_players: usize,
+    /// Whether we are the one who may launch and start the game
+    pub is_controller: bool,
+    /// Our player number, or `None` when we are a drone
+    pub consoleplayer: Option<usize>,
+    pub wad_sha1sum: [u8; 20],
+    pub deh_sha1sum: [u8; 20],
+    pub is_freedoom: bool,
+}
+
+impl From<&NetWaitData> for LobbyState {
+    fn from(data: &NetWaitData) -> Self {
+        let num_players = data.num_players.clamp(0, NET_MAXPLAYERS as i32) as usize;
+        let players = (0..num_players)
+            .map(|i| LobbyPlayer {
+                name: player_string_to_string(&data.player_names[i]),
+                address: player_string_to_string(&data.player_addrs[i]),
+            })
+            .collect();
+
+        LobbyState {
+            players,
+            ready_players: data.ready_players.max(0) as usize,
+            num_drones: data.num_drones.max(0) as usize,
+            max_players: data.max_players.max(0) as usize,
+            is_controller: data.is_controller != 0,
+            consoleplayer: usize::try_from(data.consoleplayer).ok(),
+            wad_sha1sum: data.wad_sha1sum,
+            deh_sha1sum: data.deh_sha1sum,
+            is_freedoom: data.is_freedoom != 0,
+        }
+    }
+}
+
+/// Returns a NUL padded player name or address as a `String`.
+pub fn player_string_to_string(s: &[char; MAXPLAYERNAME]) -> String {
+    s.iter().take_while(|&&c| c != '\0').collect()
+}
+
 #[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum ClientState {
     #[default]
@@ -164,47 +373,60 @@ pub enum ClientState {
     DisconnectedSleep,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Reliable packet that is retransmitted until the other end acknowledges it.
+#[derive(Debug, Clone)]
+pub struct ReliablePacket {
+    pub packet: NetPacket,
+    pub last_send_time: Option<Instant>,
+    pub seq: u8,
+}
+
+#[derive(Debug, Clone)]
 pub struct NetConnection {
     pub state: ConnectionState,
+    pub disconnect_reason: Option<DisconnectReason>,
     pub addr: SocketAddr,
+    pub protocol: NetProtocol,
+    pub last_send_time: Option<Instant>,
+    pub num_retries: u32,
+    pub keepalive_send_time: Instant,
+    pub keepalive_recv_time: Instant,
+    pub reliable_packets: VecDeque<ReliablePacket>,
+    pub reliable_send_seq: u8,
+    pub reliable_recv_seq: u8,
 }
 
 impl NetConnection {
     pub fn new(addr: SocketAddr) -> Self {
         Self {
             state: ConnectionState::Disconnected,
+            disconnect_reason: None,
             addr,
+            protocol: NetProtocol::Unknown,
+            last_send_time: None,
+            num_retries: 0,
+            keepalive_send_time: Instant::now(),
+            keepalive_recv_time: Instant::now(),
+            reliable_packets: VecDeque::new(),
+            reliable_send_seq: 0,
+            reliable_recv_seq: 0,
         }
     }
 }
 
 impl Default for NetConnection {
     fn default() -> Self {
-        Self {
-            state: ConnectionState::default(),
-            addr: "127.0.0.1:8080".parse().unwrap(),
-        }
+        Self::new("127.0.0.1:8080".parse().unwrap())
     }
 }
 
-#[derive(Clone)]
+#[derive(Clone, Default)]
 pub struct NetServerRecv {
     pub active: bool,
-    pub resend_time: Instant,
+    pub resend_time: Option<Instant>,
     pub cmd: NetFullTicCmd,
 }
 
-impl Default for NetServerRecv {
-    fn default() -> Self {
-        Self {
-            active: false,
-            resend_time: Instant::now(),
-            cmd: Default::default(),
-        }
-    }
-}
-
 #[derive(Clone)]
 pub struct NetServerSend {
     pub active: bool,
@@ -224,12 +446,49 @@ impl Default for NetServerSend {
     }
 }
 
+/// Ticcmd received by the server from one player, held in its receive
+/// window until every client has acknowledged the tic.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NetClientRecv {
+    pub active: bool,
+    pub resend_time: Option<Instant>,
+    pub latency: i32,
+    pub diff: NetTicDiff,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ServerState {
+    /// Waiting for the controller to launch the game
+    #[default]
+    WaitingLaunch,
+    /// Launched, waiting for the controller to send the game settings
+    WaitingStart,
+    InGame,
+}
+
 #[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum ConnectionState {
     #[default]
     Disconnected,
+    /// Sent a SYN, waiting for a SYN in response
     Connecting,
     Connected,
+    /// Sent a DISCONNECT, waiting for a DISCONNECT_ACK in response
+    Disconnecting,
+    /// Disconnected, but waiting a few seconds in case the DISCONNECT_ACK
+    /// we sent was lost and the other end asks again
+    DisconnectedSleep,
+}
+
+/// Reason a connection was terminated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DisconnectReason {
+    /// As the result of a local disconnect request
+    Local,
+    /// As the result of a remote disconnect request
+    Remote,
+    /// No data received from the other end in a long time
+    Timeout,
 }
 
 #[derive(Debug, Clone)]
@@ -239,3 +498,57 @@ pub struct SendQueueEntry {
     pub time: Instant,
     pub cmd: NetTicDiff,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_bare_version_mismatch() {
+        let reject =
+            VersionReject::parse("Version mismatch: server version is: Chocolate Doom 2.2.1");
+        assert_eq!(
+            reject,
+            Some(VersionReject::VersionMismatch {
+                server_version: "Chocolate Doom 2.2.1".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_version_mismatch_with_client_version() {
+        let reject = VersionReject::parse(
+            "Version mismatch: server version is: Chocolate Doom 3.0.1; \
+            client is: Chocolate Doom 2.2.1. \
+            No common compatible protocol could be negotiated.",
+        );
+        assert_eq!(
+            reject,
+            Some(VersionReject::VersionMismatch {
+                server_version: "Chocolate Doom 3.0.1".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_old_client() {
+        let reject = VersionReject::parse(
+            "You are using an old client version that is not supported by this server. \
+            This server is running Chocolate Doom 3.0.1.",
+        );
+        assert_eq!(
+            reject.as_ref().map(VersionReject::server_version),
+            Some("Chocolate Doom 3.0.1")
+        );
+        assert!(matches!(reject, Some(VersionReject::OldClient { .. })));
+    }
+
+    #[test]
+    fn test_parse_other_reject() {
+        assert_eq!(VersionReject::parse("Server is full!"), None);
+        assert_eq!(
+            VersionReject::parse("Version mismatch: server version is: "),
+            None
+        );
+    }
+}